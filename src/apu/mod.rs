@@ -0,0 +1,528 @@
+mod noise;
+mod recorder;
+mod square;
+mod wave;
+
+use noise::Noise;
+use recorder::Recorder;
+use serde::{Deserialize, Serialize};
+use square::Square;
+use std::path::Path;
+use wave::Wave;
+
+// Sources:
+// https://gbdev.io/pandocs/Audio.html
+// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware
+//
+// Everything runs in T-cycles of the normal speed clock. Registers are kept as written for
+// reading back (io.rs ORs in the bits that read as 1), the channels decode what they need.
+
+pub const SAMPLE_RATE: u32 = 48000;
+const CPU_FREQUENCY: u32 = 4_194_304;
+
+// The frame sequencer runs at 512 Hz
+const FRAME_SEQUENCER_TICKS: u32 = CPU_FREQUENCY / 512;
+
+// Charge factor of the high-pass filter applied on the output, per sample
+const CAPACITOR_CHARGE: f32 = 0.996;
+
+// Samples of each channel kept for the channel viewer
+pub const SCOPE_LEN: usize = 1024;
+
+// Volume envelope shared by the square and noise channels (NR12, NR22, NR42)
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        self.initial_volume = data >> 4;
+        self.increase = data & 0x08 > 0;
+        self.period = data & 0x07;
+    }
+
+    // The DAC is powered as long as the envelope isn't set to silence
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume > 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+// What the channel viewer shows of a channel
+pub struct ChannelStatus {
+    pub enabled: bool,
+    // Hz, of the waveform (of the LFSR clock for the noise channel)
+    pub frequency: f32,
+    pub volume: u8, // 0~15
+    // Increasing, period (0 when stopped)
+    pub envelope: Option<(bool, u8)>,
+    // Duty cycle, wave volume or LFSR width
+    pub mode: &'static str,
+}
+
+// Length counter: disables the channel when it reaches 0, if enabled in NRx4
+#[derive(Serialize, Deserialize)]
+struct Length {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    // NRx1 holds the length already elapsed
+    fn load(&mut self, data: u8) {
+        self.counter = self.max - data as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns false when the channel must be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter > 0;
+        }
+        true
+    }
+}
+
+// Digital value (0~15) to the analog output of a DAC, between -1 and 1
+fn dac(enabled: bool, value: u8) -> f32 {
+    if enabled {
+        value as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+// NR51 routes each channel to the left and/or right output, NR50 sets their volume
+fn mix(outputs: &[f32; 4], nr50: u8, nr51: u8) -> (f32, f32) {
+    let mut left = 0.0;
+    let mut right = 0.0;
+    for (i, output) in outputs.iter().enumerate() {
+        if nr51 & (0x10 << i) > 0 {
+            left += output;
+        }
+        if nr51 & (0x01 << i) > 0 {
+            right += output;
+        }
+    }
+    let left = left / 4.0 * (((nr50 >> 4) & 0x07) + 1) as f32 / 8.0;
+    let right = right / 4.0 * ((nr50 & 0x07) + 1) as f32 / 8.0;
+    (left, right)
+}
+
+// Removes the DC offset like the capacitors on the real output
+#[derive(Serialize, Deserialize)]
+struct HighPass {
+    capacitors: [f32; 2],
+}
+
+impl HighPass {
+    fn new() -> HighPass {
+        HighPass {
+            capacitors: [0.0; 2],
+        }
+    }
+
+    fn apply(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let mut output = [left, right];
+        for (sample, capacitor) in output.iter_mut().zip(self.capacitors.iter_mut()) {
+            let input = *sample;
+            *sample = input - *capacitor;
+            *capacitor = input - *sample * CAPACITOR_CHARGE;
+        }
+        (output[0], output[1])
+    }
+}
+
+// The frontend side (recording, muted channels, viewer and pending samples) isn't part of the
+// savestates, load_state keeps it
+#[derive(Serialize, Deserialize)]
+pub struct Apu {
+    enabled: bool,
+    #[serde(with = "crate::serde_arrays")]
+    regs: [u8; 0x30], // 0xFF10~0xFF3F
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    frame_step: u8,
+    frame_ticks: u32,
+    #[serde(skip)]
+    sample_rate: u32, // Adjusted around SAMPLE_RATE by the frame pacing
+    sample_ticks: u32,
+    high_pass: HighPass,
+    #[serde(skip)]
+    recorder: Option<Recorder>,
+    #[serde(skip)]
+    record_ticks: u32,
+    #[serde(skip)]
+    pub muted: [bool; 4], // Playback only, recordings keep every channel
+    #[serde(skip, default = "empty_scope")]
+    scope: [[f32; 4]; SCOPE_LEN],
+    #[serde(skip)]
+    scope_position: usize,
+    #[serde(skip)]
+    pub samples: Vec<f32>, // Interleaved stereo, drained by the frontend
+}
+
+fn empty_scope() -> [[f32; 4]; SCOPE_LEN] {
+    [[0.0; 4]; SCOPE_LEN]
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            enabled: false,
+            regs: [0; 0x30],
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_step: 0,
+            frame_ticks: 0,
+            sample_rate: SAMPLE_RATE,
+            sample_ticks: 0,
+            high_pass: HighPass::new(),
+            recorder: None,
+            record_ticks: 0,
+            muted: [false; 4],
+            scope: empty_scope(),
+            scope_position: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    // Restores the sound hardware from a savestate
+    pub fn load_state(&mut self, saved: Apu) {
+        *self = Apu {
+            sample_rate: self.sample_rate,
+            recorder: self.recorder.take(),
+            record_ticks: self.record_ticks,
+            muted: self.muted,
+            scope: self.scope,
+            scope_position: self.scope_position,
+            samples: std::mem::take(&mut self.samples),
+            ..saved
+        };
+    }
+
+    pub fn start_recording(&mut self, path: &Path, split_channels: bool) -> Result<(), String> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::new(path, split_channels)?);
+        self.record_ticks = 0;
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        match self.recorder.take() {
+            Some(mut recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn toggle_mute(&mut self, channel: usize) {
+        self.muted[channel] = !self.muted[channel];
+    }
+
+    // Mutes the other channels, or unmutes everything if the channel is already soloed
+    pub fn solo(&mut self, channel: usize) {
+        let soloed = (0..4).all(|i| self.muted[i] != (i == channel));
+        for (i, muted) in self.muted.iter_mut().enumerate() {
+            *muted = !soloed && i != channel;
+        }
+    }
+
+    pub fn channel_status(&self, channel: usize) -> ChannelStatus {
+        match channel {
+            0 => self.square1.status(),
+            1 => self.square2.status(),
+            2 => self.wave.status(),
+            _ => self.noise.status(),
+        }
+    }
+
+    // Last SCOPE_LEN outputs of a channel, oldest first, whether it's muted or not
+    pub fn scope(&self, channel: usize) -> impl Iterator<Item = f32> + '_ {
+        let (newest, oldest) = self.scope.split_at(self.scope_position);
+        oldest
+            .iter()
+            .chain(newest.iter())
+            .map(move |outputs| outputs[channel])
+    }
+
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF26 => {
+                ((self.enabled as u8) << 7)
+                    | ((self.noise.enabled as u8) << 3)
+                    | ((self.wave.enabled as u8) << 2)
+                    | ((self.square2.enabled as u8) << 1)
+                    | self.square1.enabled as u8
+            }
+            _ => self.regs[(addr - 0xFF10) as usize],
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF26 => return self.set_power(data & 0x80 > 0),
+            // Wave RAM is always accessible
+            0xFF30..=0xFF3F => self.wave.ram[(addr - 0xFF30) as usize] = data,
+            // The other registers ignore writes while the APU is off
+            _ if !self.enabled => return,
+            0xFF10..=0xFF14 => self.square1.write(addr - 0xFF10, data),
+            0xFF16..=0xFF19 => self.square2.write(addr - 0xFF15, data),
+            0xFF1A..=0xFF1E => self.wave.write(addr - 0xFF1A, data),
+            0xFF20..=0xFF23 => self.noise.write(addr - 0xFF1F, data),
+            _ => {}
+        }
+        self.regs[(addr - 0xFF10) as usize] = data;
+    }
+
+    // Turning the APU off clears all of its registers, except the wave RAM
+    fn set_power(&mut self, on: bool) {
+        if on && !self.enabled {
+            self.frame_step = 0;
+            self.frame_ticks = 0;
+        }
+        if !on {
+            let wave_ram = self.wave.ram;
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave = Wave::new();
+            self.wave.ram = wave_ram;
+            self.noise = Noise::new();
+            for reg in &mut self.regs[..0x16] {
+                *reg = 0;
+            }
+        }
+        self.enabled = on;
+    }
+
+    pub fn update(&mut self, ticks: u8) {
+        for _ in 0..ticks {
+            if self.enabled {
+                self.frame_ticks += 1;
+                if self.frame_ticks == FRAME_SEQUENCER_TICKS {
+                    self.frame_ticks = 0;
+                    self.clock_frame_sequencer();
+                }
+                self.square1.tick();
+                self.square2.tick();
+                self.wave.tick();
+                self.noise.tick();
+            }
+
+            self.sample_ticks += self.sample_rate;
+            if self.sample_ticks >= CPU_FREQUENCY {
+                self.sample_ticks -= CPU_FREQUENCY;
+                self.push_sample();
+            }
+
+            // Recordings ignore the rate adjustments of the playback
+            if self.recorder.is_some() {
+                self.record_ticks += SAMPLE_RATE;
+                if self.record_ticks >= CPU_FREQUENCY {
+                    self.record_ticks -= CPU_FREQUENCY;
+                    self.record_sample();
+                }
+            }
+        }
+    }
+
+    // Length counters at 256 Hz, sweep at 128 Hz and envelopes at 64 Hz
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn outputs(&self) -> [f32; 4] {
+        [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ]
+    }
+
+    fn push_sample(&mut self) {
+        let mut outputs = self.outputs();
+        self.scope[self.scope_position] = outputs;
+        self.scope_position = (self.scope_position + 1) % SCOPE_LEN;
+
+        for (output, &muted) in outputs.iter_mut().zip(self.muted.iter()) {
+            if muted {
+                *output = 0.0;
+            }
+        }
+        let (left, right) = mix(&outputs, self.regs[0x14], self.regs[0x15]);
+        let (left, right) = self.high_pass.apply(left, right);
+        self.samples.push(left);
+        self.samples.push(right);
+    }
+
+    fn record_sample(&mut self) {
+        let outputs = self.outputs();
+        let (nr50, nr51) = (self.regs[0x14], self.regs[0x15]);
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(&outputs, nr50, nr51) {
+                eprintln!("Warning: stopped recording audio ({})", e);
+                self.recorder = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Apu, FRAME_SEQUENCER_TICKS};
+
+    // Powered on, with channel 1 at full volume
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF12, 0xF0);
+        apu
+    }
+
+    fn run_frame_sequencer(apu: &mut Apu, steps: u32) {
+        for _ in 0..steps * FRAME_SEQUENCER_TICKS / 4 {
+            apu.update(4);
+        }
+    }
+
+    fn channel_1_on(apu: &Apu) -> bool {
+        apu.read(0xFF26) & 0x01 > 0
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut apu = powered_apu();
+        // 2 steps of length left, length enabled and trigger
+        apu.write(0xFF11, 0x3E);
+        apu.write(0xFF14, 0xC0);
+        assert!(channel_1_on(&apu));
+
+        // Steps 0 and 1, only the first one clocks the length
+        run_frame_sequencer(&mut apu, 2);
+        assert!(channel_1_on(&apu));
+        run_frame_sequencer(&mut apu, 1);
+        assert!(!channel_1_on(&apu));
+    }
+
+    #[test]
+    fn length_counter_ignored_when_disabled() {
+        let mut apu = powered_apu();
+        apu.write(0xFF11, 0x3E);
+        apu.write(0xFF14, 0x80);
+        run_frame_sequencer(&mut apu, 8);
+        assert!(channel_1_on(&apu));
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        // Frequency 2047, the first sweep would overflow: checked on trigger
+        let mut apu = powered_apu();
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF13, 0xFF);
+        apu.write(0xFF14, 0x87);
+        assert!(!channel_1_on(&apu));
+
+        // Frequency 1000 goes to 1500, then the check of the next one (2250) overflows
+        let mut apu = powered_apu();
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF13, 0xE8);
+        apu.write(0xFF14, 0x83);
+        assert!(channel_1_on(&apu));
+
+        // The sweep is clocked on step 2
+        run_frame_sequencer(&mut apu, 2);
+        assert!(channel_1_on(&apu));
+        run_frame_sequencer(&mut apu, 1);
+        assert!(!channel_1_on(&apu));
+    }
+
+    #[test]
+    fn frame_sequencer_clocks_envelope_on_step_7() {
+        let mut apu = powered_apu();
+        // Volume 15, decreasing every envelope clock
+        apu.write(0xFF12, 0xF1);
+        apu.write(0xFF14, 0x80);
+
+        run_frame_sequencer(&mut apu, 7);
+        assert_eq!(apu.channel_status(0).volume, 15);
+        run_frame_sequencer(&mut apu, 1);
+        assert_eq!(apu.channel_status(0).volume, 14);
+        run_frame_sequencer(&mut apu, 8);
+        assert_eq!(apu.channel_status(0).volume, 13);
+    }
+}
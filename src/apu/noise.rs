@@ -0,0 +1,108 @@
+use super::{dac, ChannelStatus, Envelope, Length, CPU_FREQUENCY};
+use serde::{Deserialize, Serialize};
+
+// Base periods selected by the lower bits of NR43
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Channel 4: pseudo-random output from a linear feedback shift register
+#[derive(Serialize, Deserialize)]
+pub struct Noise {
+    pub enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    shift: u8,
+    short_mode: bool, // 7-bit LFSR instead of 15-bit
+    divisor: usize,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            short_mode: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    // Registers NR41~NR44 (reg 0 is unused)
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            1 => self.length.load(data & 0x3F),
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = data >> 4;
+                self.short_mode = data & 0x08 > 0;
+                self.divisor = (data & 0x07) as usize;
+            }
+            4 => {
+                self.length.enabled = data & 0x40 > 0;
+                if data & 0x80 > 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor] << self.shift
+    }
+
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            let feedback = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn output(&self) -> f32 {
+        // The output is the inverted lowest bit
+        let value = if self.enabled && self.lfsr & 0x01 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        };
+        dac(self.envelope.dac_enabled(), value)
+    }
+
+    pub fn status(&self) -> ChannelStatus {
+        ChannelStatus {
+            enabled: self.enabled,
+            frequency: CPU_FREQUENCY as f32 / self.period() as f32,
+            volume: self.envelope.volume,
+            envelope: Some((self.envelope.increase, self.envelope.period)),
+            mode: if self.short_mode { "7-bit" } else { "15-bit" },
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use super::{mix, HighPass, SAMPLE_RATE};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Source: http://soundfile.sapp.org/doc/WaveFormat/
+
+// 16-bit stereo PCM
+struct WavWriter {
+    file: BufWriter<File>,
+    frames: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path) -> Result<WavWriter, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Unable to create {}: {}", path.display(), e))?;
+        let mut wav = WavWriter {
+            file: BufWriter::new(file),
+            frames: 0,
+        };
+        wav.write_header().map_err(|e| e.to_string())?;
+        Ok(wav)
+    }
+
+    // The sizes are patched in by finish()
+    fn write_header(&mut self) -> std::io::Result<()> {
+        let data_size = self.frames * 4;
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&(36 + data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVEfmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?; // Format chunk size
+        self.file.write_all(&1u16.to_le_bytes())?; // PCM
+        self.file.write_all(&2u16.to_le_bytes())?; // Channels
+        self.file.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        self.file.write_all(&(SAMPLE_RATE * 4).to_le_bytes())?; // Bytes per second
+        self.file.write_all(&4u16.to_le_bytes())?; // Bytes per frame
+        self.file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        self.file.write_all(b"data")?;
+        self.file.write_all(&data_size.to_le_bytes())
+    }
+
+    fn write(&mut self, left: f32, right: f32) -> std::io::Result<()> {
+        for sample in [left, right].iter() {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+// Writes the mixed output, and optionally each channel on its own, at exactly SAMPLE_RATE.
+// It's fed by the APU itself, so it doesn't depend on the audio device or the frame pacing.
+pub struct Recorder {
+    mix: (WavWriter, HighPass),
+    channels: Vec<(WavWriter, HighPass)>,
+}
+
+impl Recorder {
+    // Channels go to "<name>_ch1.wav" to "<name>_ch4.wav" next to "<name>.wav"
+    pub fn new(path: &Path, split_channels: bool) -> Result<Recorder, String> {
+        let mut channels = Vec::new();
+        if split_channels {
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("audio");
+            for i in 1..=4 {
+                let channel_path: PathBuf = path.with_file_name(format!("{}_ch{}.wav", stem, i));
+                channels.push((WavWriter::create(&channel_path)?, HighPass::new()));
+            }
+        }
+        Ok(Recorder {
+            mix: (WavWriter::create(path)?, HighPass::new()),
+            channels,
+        })
+    }
+
+    pub fn record(&mut self, outputs: &[f32; 4], nr50: u8, nr51: u8) -> std::io::Result<()> {
+        let (left, right) = mix(outputs, nr50, nr51);
+        let (left, right) = self.mix.1.apply(left, right);
+        self.mix.0.write(left, right)?;
+
+        for (i, (wav, filter)) in self.channels.iter_mut().enumerate() {
+            // Same routing and volume as in the mix, without the other channels
+            let (left, right) = mix(outputs, nr50, nr51 & (0x11 << i));
+            let (left, right) = filter.apply(left, right);
+            wav.write(left, right)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.mix.0.finish().map_err(|e| e.to_string())?;
+        for (wav, _) in &mut self.channels {
+            wav.finish().map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use super::{dac, ChannelStatus, Envelope, Length, CPU_FREQUENCY};
+use serde::{Deserialize, Serialize};
+
+// Waveforms selected by the upper bits of NRx1
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+const DUTY_NAMES: [&str; 4] = ["12.5%", "25%", "50%", "75%"];
+
+// Frequency sweep of channel 1 (NR10)
+#[derive(Serialize, Deserialize)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            shadow: 0,
+            enabled: false,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 counts as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+// Channels 1 and 2, only channel 1 has a sweep
+#[derive(Serialize, Deserialize)]
+pub struct Square {
+    pub enabled: bool,
+    sweep: Option<Sweep>,
+    length: Length,
+    envelope: Envelope,
+    duty: usize,
+    duty_step: usize,
+    frequency: u16,
+    timer: u32,
+}
+
+impl Square {
+    pub fn new(with_sweep: bool) -> Square {
+        Square {
+            enabled: false,
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    // Registers NRx0~NRx4
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.period = (data >> 4) & 0x07;
+                    sweep.negate = data & 0x08 > 0;
+                    sweep.shift = data & 0x07;
+                }
+            }
+            1 => {
+                self.duty = (data >> 6) as usize;
+                self.length.load(data & 0x3F);
+            }
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | data as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.enabled = data & 0x40 > 0;
+                if data & 0x80 > 0 {
+                    self.trigger();
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period > 0 || sweep.shift > 0;
+            // The overflow check runs right away
+            if sweep.shift > 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+
+        if sweep.enabled && sweep.period > 0 {
+            let frequency = sweep.next_frequency();
+            if frequency > 2047 {
+                self.enabled = false;
+            } else if sweep.shift > 0 {
+                sweep.shadow = frequency;
+                self.frequency = frequency;
+                // The new frequency is checked again, without being used
+                if sweep.next_frequency() > 2047 {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let value = if self.enabled {
+            DUTY_CYCLES[self.duty][self.duty_step] * self.envelope.volume
+        } else {
+            0
+        };
+        dac(self.envelope.dac_enabled(), value)
+    }
+
+    pub fn status(&self) -> ChannelStatus {
+        ChannelStatus {
+            enabled: self.enabled,
+            // A waveform is 8 steps
+            frequency: CPU_FREQUENCY as f32 / (self.period() * 8) as f32,
+            volume: self.envelope.volume,
+            envelope: Some((self.envelope.increase, self.envelope.period)),
+            mode: DUTY_NAMES[self.duty],
+        }
+    }
+}
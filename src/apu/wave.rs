@@ -0,0 +1,106 @@
+use super::{dac, ChannelStatus, Length, CPU_FREQUENCY};
+use serde::{Deserialize, Serialize};
+
+// Channel 3: plays the 32 4-bit samples of the wave RAM (0xFF30~0xFF3F)
+#[derive(Serialize, Deserialize)]
+pub struct Wave {
+    pub enabled: bool,
+    pub ram: [u8; 0x10],
+    dac_enabled: bool,
+    length: Length,
+    volume_shift: u8,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+}
+
+impl Wave {
+    pub fn new() -> Wave {
+        Wave {
+            enabled: false,
+            ram: [0; 0x10],
+            dac_enabled: false,
+            length: Length::new(256),
+            volume_shift: 4,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+        }
+    }
+
+    // Registers NR30~NR34
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = data & 0x80 > 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(data),
+            // Mute, 100%, 50% and 25%
+            2 => self.volume_shift = [4, 0, 1, 2][((data >> 5) & 0x03) as usize],
+            3 => self.frequency = (self.frequency & 0x0700) | data as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.enabled = data & 0x40 > 0;
+                if data & 0x80 > 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let value = if self.enabled {
+            // High nibble first
+            let byte = self.ram[self.position / 2];
+            let sample = if self.position.is_multiple_of(2) {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+            sample >> self.volume_shift
+        } else {
+            0
+        };
+        dac(self.dac_enabled, value)
+    }
+
+    pub fn status(&self) -> ChannelStatus {
+        ChannelStatus {
+            enabled: self.enabled,
+            // A waveform is the 32 samples
+            frequency: CPU_FREQUENCY as f32 / (self.period() * 32) as f32,
+            volume: 15 >> self.volume_shift,
+            envelope: None,
+            mode: match self.volume_shift {
+                0 => "100%",
+                1 => "50%",
+                2 => "25%",
+                _ => "mute",
+            },
+        }
+    }
+}
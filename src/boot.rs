@@ -0,0 +1,152 @@
+use crate::hardware::Cpu;
+use crate::memory::Memory;
+use std::fmt;
+
+// Sources:
+// https://gbdev.io/pandocs/Power_Up_Sequence.html
+// https://github.com/Gekkio/mooneye-test-suite (boot_regs and boot_hwio tests)
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Model::Dmg0 => write!(f, "DMG0"),
+            Model::Dmg => write!(f, "DMG"),
+            Model::Mgb => write!(f, "MGB"),
+            Model::Sgb => write!(f, "SGB"),
+            Model::Sgb2 => write!(f, "SGB2"),
+            Model::Cgb => write!(f, "CGB"),
+        }
+    }
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "dmg0" => Some(Model::Dmg0),
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "sgb2" => Some(Model::Sgb2),
+            "cgb" => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        *self == Model::Cgb
+    }
+}
+
+// CPU state left by the boot ROM. A is what games check to tell the models apart.
+// On DMG and MGB, H and C depend on the header checksum computed by the boot ROM.
+pub fn init_registers(model: Model, cpu: &mut Cpu, header_checksum: u8) {
+    let checksum_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+
+    // A, F, B, C, D, E, H, L
+    let registers = match model {
+        Model::Dmg0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+        Model::Dmg => [0x01, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+        Model::Mgb => [0xFF, checksum_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+        Model::Sgb => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+        Model::Sgb2 => [0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+        Model::Cgb => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+    };
+
+    cpu.a = registers[0];
+    cpu.f = registers[1];
+    cpu.b = registers[2];
+    cpu.c = registers[3];
+    cpu.d = registers[4];
+    cpu.e = registers[5];
+    cpu.h = registers[6];
+    cpu.l = registers[7];
+    cpu.sp = 0xFFFE;
+    cpu.pc = 0x0100;
+}
+
+// I/O registers left by the boot ROM
+pub fn init_io(model: Model, mem: &mut Memory) {
+    let io: &[(u16, u8)] = &[
+        (0xFF00, 0xCF), // P1
+        (0xFF0F, 0xE1), // IF
+        (0xFF40, 0x91), // LCDC
+        (0xFF42, 0x00), // SCY
+        (0xFF43, 0x00), // SCX
+        (0xFF44, 0x00), // LY
+        (0xFF45, 0x00), // LYC
+        (0xFF46, 0xFF), // DMA
+        (0xFF47, 0xFC), // BGP
+        (0xFF48, 0xFF), // OBP0
+        (0xFF49, 0xFF), // OBP1
+        (0xFF4A, 0x00), // WY
+        (0xFF4B, 0x00), // WX
+        (0xFFFF, 0x00), // IE
+    ];
+    for &(addr, value) in io {
+        mem.ram[(addr & 0x7FFF) as usize] = value;
+    }
+
+    // SB, SC, TIMA, TMA and TAC start at 0, only the divider has been running
+    let (divider, stat) = match model {
+        Model::Dmg0 => (0x1800, 0x81),
+        Model::Dmg | Model::Mgb => (0xABCC, 0x85),
+        Model::Sgb | Model::Sgb2 => (0x0000, 0x85), // DIV depends on the SGB BIOS timing
+        Model::Cgb => (0x0000, 0x85),               // DIV depends on the game header
+    };
+    mem.timer.divider = divider;
+    mem.ram[0x7F41] = stat;
+
+    // The APU is left on after the boot sound. Written without the trigger bits, so channel 1
+    // doesn't count as playing (NR52 reads 0xF0 instead of 0xF1).
+    let sound: &[(u16, u8)] = &[
+        (0xFF26, 0x80), // NR52
+        (0xFF10, 0x80), // NR10
+        (0xFF11, 0xBF), // NR11
+        (0xFF12, 0xF3), // NR12
+        (0xFF13, 0xFF), // NR13
+        (0xFF14, 0x3F), // NR14
+        (0xFF16, 0x3F), // NR21
+        (0xFF17, 0x00), // NR22
+        (0xFF18, 0xFF), // NR23
+        (0xFF19, 0x3F), // NR24
+        (0xFF1A, 0x7F), // NR30
+        (0xFF1B, 0xFF), // NR31
+        (0xFF1C, 0x9F), // NR32
+        (0xFF1D, 0xFF), // NR33
+        (0xFF1E, 0x3F), // NR34
+        (0xFF20, 0xFF), // NR41
+        (0xFF21, 0x00), // NR42
+        (0xFF22, 0x00), // NR43
+        (0xFF23, 0x3F), // NR44
+        (0xFF24, 0x77), // NR50
+        (0xFF25, 0xF3), // NR51
+    ];
+    for &(addr, value) in sound {
+        mem.write(addr, value);
+    }
+
+    if model.is_cgb() {
+        let cgb_io: &[(u16, u8)] = &[
+            (0xFF4D, 0x7E), // KEY1
+            (0xFF4F, 0xFE), // VBK
+            (0xFF55, 0xFF), // HDMA5
+            (0xFF56, 0x3E), // RP
+            (0xFF68, 0xC0), // BCPS
+            (0xFF6A, 0xC1), // OCPS
+            (0xFF70, 0xF8), // SVBK
+        ];
+        for &(addr, value) in cgb_io {
+            mem.ram[(addr & 0x7FFF) as usize] = value;
+        }
+    }
+}
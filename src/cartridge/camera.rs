@@ -0,0 +1,365 @@
+use super::Cartridge;
+use sdl2::image::LoadSurface;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::surface::Surface;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Sources:
+// https://gbdev.io/pandocs/Gameboy_Camera.html
+// https://github.com/AntonioND/gbcam-rev-engineer
+//
+// Selecting RAM bank 0x10 or above maps the M64282FP sensor registers at 0xA000~0xA07F.
+// A capture runs the sensor output through exposure, gain, edge enhancement and finally the
+// 4x4 dithering matrix, and writes the resulting 128x112 picture as tiles in RAM bank 0.
+
+pub const SENSOR_WIDTH: usize = 128;
+pub const SENSOR_HEIGHT: usize = 112;
+
+const NB_REGISTERS: usize = 0x36;
+const REG_CONTROL: usize = 0x00;
+const REG_GAIN_EDGE: usize = 0x01;
+const REG_EXPOSURE_HIGH: usize = 0x02;
+const REG_EXPOSURE_LOW: usize = 0x03;
+const REG_EDGE_RATIO_INVERT: usize = 0x04;
+const REG_OFFSET: usize = 0x05;
+const REG_MATRIX: usize = 0x06;
+
+// Where the captured picture is written in RAM bank 0 (16x14 tiles)
+const IMAGE_OFFSET: usize = 0x0100;
+
+// The capture takes 32446 cycles, plus 512 when N is reset and 16 per exposure step.
+// The sensor runs at 1 MiHz, values are converted to CPU ticks.
+const CAPTURE_BASE_CYCLES: u32 = 32446;
+
+// Exposure giving the raw sensor value back unchanged, the games adjust around it
+const NEUTRAL_EXPOSURE: u32 = 0x0800;
+
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+// Where the sensor gets its light from
+pub enum ImageSource {
+    File(Vec<u8>),
+    Frames(Vec<PathBuf>, usize), // Sorted frames, next frame to use
+    TestPattern(u8),             // Frame counter, used to animate the pattern
+}
+
+impl Default for ImageSource {
+    fn default() -> ImageSource {
+        ImageSource::TestPattern(0)
+    }
+}
+
+impl ImageSource {
+    // A file is used as is, a directory is played as a sequence of frames,
+    // and nothing (or "pattern") gives a synthetic test pattern
+    pub fn new(path: Option<&Path>) -> Result<ImageSource, String> {
+        match path {
+            Some(path) if path.is_dir() => {
+                let mut frames: Vec<PathBuf> = fs::read_dir(path)
+                    .map_err(|e| format!("Unable to read camera frames folder: {}", e))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| {
+                        let extension = path.extension().and_then(|e| e.to_str());
+                        matches!(
+                            extension,
+                            Some("png") | Some("bmp") | Some("jpg") | Some("jpeg")
+                        )
+                    })
+                    .collect();
+                frames.sort();
+                if frames.is_empty() {
+                    return Err(format!("No camera frames found in {}", path.display()));
+                }
+                Ok(ImageSource::Frames(frames, 0))
+            }
+            Some(path) if path.to_str() != Some("pattern") => {
+                Ok(ImageSource::File(ImageSource::load_frame(path)?))
+            }
+            _ => Ok(ImageSource::TestPattern(0)),
+        }
+    }
+
+    // Decode an image and reduce it to a grayscale 128x112 sensor frame.
+    // The image is cropped to the sensor aspect ratio around its center, then scaled.
+    fn load_frame(path: &Path) -> Result<Vec<u8>, String> {
+        let surface = Surface::from_file(path)
+            .map_err(|e| format!("Unable to load camera image {}: {}", path.display(), e))?;
+        let surface = surface
+            .convert_format(PixelFormatEnum::RGB24)
+            .map_err(|e| format!("Unable to convert camera image: {}", e))?;
+
+        let (width, height) = (surface.width() as usize, surface.height() as usize);
+        let pitch = surface.pitch() as usize;
+        let (crop_w, crop_h) = if width * SENSOR_HEIGHT > height * SENSOR_WIDTH {
+            (height * SENSOR_WIDTH / SENSOR_HEIGHT, height)
+        } else {
+            (width, width * SENSOR_HEIGHT / SENSOR_WIDTH)
+        };
+        let (left, top) = ((width - crop_w) / 2, (height - crop_h) / 2);
+
+        Ok(surface.with_lock(|pixels| {
+            let mut frame = vec![0; SENSOR_WIDTH * SENSOR_HEIGHT];
+            for y in 0..SENSOR_HEIGHT {
+                for x in 0..SENSOR_WIDTH {
+                    let src_x = left + x * crop_w / SENSOR_WIDTH;
+                    let src_y = top + y * crop_h / SENSOR_HEIGHT;
+                    let offset = src_y * pitch + src_x * 3;
+                    let (r, g, b) = (
+                        pixels[offset] as u32,
+                        pixels[offset + 1] as u32,
+                        pixels[offset + 2] as u32,
+                    );
+                    frame[y * SENSOR_WIDTH + x] = ((299 * r + 587 * g + 114 * b) / 1000) as u8;
+                }
+            }
+            frame
+        }))
+    }
+
+    fn next_frame(&mut self) -> Vec<u8> {
+        match self {
+            ImageSource::File(frame) => frame.clone(),
+            ImageSource::Frames(frames, next) => {
+                // A frame that can't be loaded anymore reads as darkness
+                let frame = ImageSource::load_frame(&frames[*next]).unwrap_or_else(|e| {
+                    eprintln!("Warning: {}", e);
+                    vec![0; SENSOR_WIDTH * SENSOR_HEIGHT]
+                });
+                *next = (*next + 1) % frames.len();
+                frame
+            }
+            ImageSource::TestPattern(counter) => {
+                // Gradient bars with a square drifting across them
+                *counter = counter.wrapping_add(1);
+                let square_x = *counter as usize % SENSOR_WIDTH;
+                let mut frame = vec![0; SENSOR_WIDTH * SENSOR_HEIGHT];
+                for y in 0..SENSOR_HEIGHT {
+                    for x in 0..SENSOR_WIDTH {
+                        let in_square = x >= square_x
+                            && x < square_x + 24
+                            && (SENSOR_HEIGHT / 2 - 12..SENSOR_HEIGHT / 2 + 12).contains(&y);
+                        frame[y * SENSOR_WIDTH + x] = if in_square {
+                            0xFF - (x * 2) as u8
+                        } else {
+                            ((x / 16) * 32 + y / 4) as u8
+                        };
+                    }
+                }
+                frame
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Camera {
+    pub registers_mapped: bool,
+    #[serde(with = "crate::serde_arrays")]
+    registers: [u8; NB_REGISTERS],
+    capture_ticks: u32, // Remaining ticks before the capture completes, 0 when idle
+    #[serde(skip)]
+    source: ImageSource,
+}
+
+impl Camera {
+    pub fn new(source: ImageSource) -> Camera {
+        Camera {
+            registers_mapped: false,
+            registers: [0; NB_REGISTERS],
+            capture_ticks: 0,
+            source,
+        }
+    }
+
+    // Restores the sensor from a savestate, the pictures keep coming from the same source
+    pub fn load_state(&mut self, saved: Camera) {
+        *self = Camera {
+            source: std::mem::take(&mut self.source),
+            ..saved
+        };
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        // Only the control register can be read, bit 0 stays set while capturing
+        match (addr & 0x7F) as usize {
+            REG_CONTROL => {
+                (self.registers[REG_CONTROL] & 0b0000_0110) | (self.capture_ticks > 0) as u8
+            }
+            _ => 0x00,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        let reg = (addr & 0x7F) as usize;
+        if reg >= NB_REGISTERS {
+            return;
+        }
+        self.registers[reg] = data;
+
+        if reg == REG_CONTROL && data & 0b1 > 0 && self.capture_ticks == 0 {
+            let exposure = self.exposure();
+            let n_cycles = if self.registers[REG_GAIN_EDGE] & 0b1000_0000 > 0 {
+                0
+            } else {
+                512
+            };
+            self.capture_ticks = (CAPTURE_BASE_CYCLES + n_cycles + exposure * 16) * 4;
+        }
+    }
+
+    fn exposure(&self) -> u32 {
+        ((self.registers[REG_EXPOSURE_HIGH] as u32) << 8) | self.registers[REG_EXPOSURE_LOW] as u32
+    }
+
+    // Returns true when a capture just completed
+    pub fn update(&mut self, ticks: u8) -> bool {
+        if self.capture_ticks == 0 {
+            return false;
+        }
+        self.capture_ticks = self.capture_ticks.saturating_sub(ticks as u32);
+        if self.capture_ticks == 0 {
+            self.registers[REG_CONTROL] &= !0b1;
+            return true;
+        }
+        false
+    }
+
+    // Analog part of the sensor: exposure, gain, offset, inversion and edge enhancement
+    fn process(&self, frame: &[u8]) -> Vec<i32> {
+        let gain_reg = self.registers[REG_GAIN_EDGE];
+        let ratio_reg = self.registers[REG_EDGE_RATIO_INVERT];
+        let offset_reg = self.registers[REG_OFFSET];
+
+        // Gain goes from 14 dB upwards in ~1.5 dB steps, 14 dB is used as the unit gain
+        let gain = 10f32.powf(1.5 * (gain_reg & 0b0001_1111) as f32 / 20.0);
+        let exposure = self.exposure() as f32 / NEUTRAL_EXPOSURE as f32;
+        // Signed output offset: bit 5 is the sign (set = positive), bits 0-4 the magnitude
+        let magnitude = ((offset_reg & 0b0001_1111) as i32) * 2;
+        let offset = if offset_reg & 0b0010_0000 > 0 {
+            magnitude
+        } else {
+            -magnitude
+        };
+        let invert = ratio_reg & 0b0000_1000 > 0;
+
+        let exposed: Vec<i32> = frame
+            .iter()
+            .map(|&value| {
+                let value = ((value as f32 * exposure * gain) as i32 + offset).clamp(0, 255);
+                if invert {
+                    255 - value
+                } else {
+                    value
+                }
+            })
+            .collect();
+
+        let ratio = EDGE_RATIOS[((ratio_reg >> 4) & 0b111) as usize];
+        let pixel = |x: isize, y: isize| {
+            let x = x.clamp(0, SENSOR_WIDTH as isize - 1) as usize;
+            let y = y.clamp(0, SENSOR_HEIGHT as isize - 1) as usize;
+            exposed[y * SENSOR_WIDTH + x]
+        };
+
+        let mut processed = exposed.clone();
+        for y in 0..SENSOR_HEIGHT as isize {
+            for x in 0..SENSOR_WIDTH as isize {
+                let center = pixel(x, y);
+                let horizontal = 2 * center - pixel(x - 1, y) - pixel(x + 1, y);
+                let vertical = 2 * center - pixel(x, y - 1) - pixel(x, y + 1);
+                // VH bits select the edge operation
+                let edge = match (gain_reg >> 5) & 0b11 {
+                    0b01 => horizontal,
+                    0b10 => vertical,
+                    0b11 => horizontal + vertical,
+                    _ => 0,
+                };
+                processed[y as usize * SENSOR_WIDTH + x as usize] =
+                    center + (edge as f32 * ratio) as i32;
+            }
+        }
+        processed
+    }
+
+    // Take a picture and write it as tiles into the RAM bank
+    pub fn capture(&mut self, ram_bank: &mut [u8; 0x2000]) {
+        let frame = self.source.next_frame();
+        let processed = self.process(&frame);
+
+        for y in 0..SENSOR_HEIGHT {
+            for x in 0..SENSOR_WIDTH {
+                // Each pixel has 3 thresholds from the 4x4 matrix, the darkest color is 3
+                let matrix = REG_MATRIX + ((x & 3) + (y & 3) * 4) * 3;
+                let value = processed[y * SENSOR_WIDTH + x];
+                let color: u8 = if value < self.registers[matrix] as i32 {
+                    3
+                } else if value < self.registers[matrix + 1] as i32 {
+                    2
+                } else if value < self.registers[matrix + 2] as i32 {
+                    1
+                } else {
+                    0
+                };
+
+                let tile = (y / 8) * (SENSOR_WIDTH / 8) + x / 8;
+                let offset = IMAGE_OFFSET + tile * 16 + (y % 8) * 2;
+                let bit = 0b1000_0000 >> (x % 8);
+                let planes = [color & 0b01 > 0, color & 0b10 > 0];
+                for (plane, set) in planes.iter().enumerate() {
+                    if *set {
+                        ram_bank[offset + plane] |= bit;
+                    } else {
+                        ram_bank[offset + plane] &= !bit;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Cartridge {
+    pub(super) fn write_camera_control(&mut self, addr: u16, data: u8) {
+        match addr {
+            // RAM write enable, reading is always possible
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            // 6-bit ROM bank number, bank 0 can be mapped at 0x4000
+            0x2000..=0x3FFF => self.active_bank = (data & 0b0011_1111) as u16,
+            // RAM bank number, or the sensor registers when bit 4 is set
+            0x4000..=0x5FFF => {
+                if let Some(camera) = &mut self.camera {
+                    camera.registers_mapped = data & 0b0001_0000 > 0;
+                }
+                self.active_ram_bank = data & 0b0000_1111;
+            }
+            // No effect
+            _ => {}
+        }
+    }
+
+    pub(super) fn read_camera(&self, addr: u16) -> u8 {
+        match &self.camera {
+            Some(camera) if camera.registers_mapped => camera.read_register(addr),
+            _ => {
+                let bank = self.active_ram_bank as usize % self.ram_banks.len();
+                self.ram_banks[bank][(addr & 0x1FFF) as usize]
+            }
+        }
+    }
+
+    pub(super) fn write_camera(&mut self, addr: u16, data: u8) {
+        match &mut self.camera {
+            Some(camera) if camera.registers_mapped => camera.write_register(addr, data),
+            _ => self.write_ram_bank(addr, data),
+        }
+    }
+
+    pub(super) fn update_camera(&mut self, ticks: u8) {
+        if let Some(camera) = &mut self.camera {
+            if camera.update(ticks) {
+                camera.capture(&mut self.ram_banks[0]);
+            }
+        }
+    }
+}
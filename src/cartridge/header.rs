@@ -0,0 +1,420 @@
+use super::{CartridgeKind, NINTENDO_LOGO};
+use serde::Serialize;
+use std::fmt;
+
+// Sources:
+// https://gbdev.io/pandocs/The_Cartridge_Header.html
+
+#[derive(Default, Serialize)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: u8,
+    pub cgb_support: &'static str,
+    pub sgb_support: bool,
+    pub cartridge_type: u8,
+    pub cartridge_kind: Option<String>,
+    pub rom_size_code: u8,
+    pub rom_size: Option<usize>,
+    pub ram_size_code: u8,
+    pub ram_size: Option<usize>,
+    pub destination: &'static str,
+    pub old_licensee_code: u8,
+    pub new_licensee_code: Option<String>,
+    pub licensee: &'static str,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub header_checksum_valid: bool,
+    pub global_checksum: u16,
+    pub global_checksum_valid: bool,
+    pub logo_valid: bool,
+    // Bank holding the header (not bank 0 for MMM01 multicarts)
+    pub bank: usize,
+}
+
+impl CartridgeHeader {
+    pub fn from_rom(banks: &[[u8; 0x4000]]) -> CartridgeHeader {
+        let bank = CartridgeHeader::find_header_bank(banks);
+        let header = &banks[bank];
+
+        let cgb_flag = header[0x0143];
+        let cgb_support = match cgb_flag {
+            0xC0 => "CGB only",
+            0x80 => "CGB enhanced",
+            _ => "DMG",
+        };
+
+        // On CGB-era cartridges the title shrank to make room for the manufacturer code and CGB flag
+        let manufacturer = &header[0x013F..0x0143];
+        let manufacturer_code =
+            if cgb_flag & 0x80 > 0 && manufacturer.iter().all(u8::is_ascii_uppercase) {
+                Some(String::from_utf8_lossy(manufacturer).to_string())
+            } else {
+                None
+            };
+        let title_end = match (&manufacturer_code, cgb_flag & 0x80 > 0) {
+            (Some(_), _) => 0x013F,
+            (None, true) => 0x0143,
+            (None, false) => 0x0144,
+        };
+        let title: String = header[0x0134..title_end]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| {
+                if c.is_ascii_graphic() || c == b' ' {
+                    c as char
+                } else {
+                    '?'
+                }
+            })
+            .collect();
+
+        let old_licensee_code = header[0x014B];
+        let new_licensee_code = if old_licensee_code == 0x33 {
+            Some(String::from_utf8_lossy(&header[0x0144..0x0146]).to_string())
+        } else {
+            None
+        };
+        let licensee = match &new_licensee_code {
+            Some(code) => new_licensee(code),
+            None => old_licensee(old_licensee_code),
+        };
+
+        let cartridge_type = header[0x0147];
+        let rom_size_code = header[0x0148];
+        let ram_size_code = header[0x0149];
+
+        let header_checksum = header[0x014D];
+        let computed_header_checksum = header[0x0134..=0x014C]
+            .iter()
+            .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+
+        let global_checksum = ((header[0x014E] as u16) << 8) | header[0x014F] as u16;
+        let computed_global_checksum = banks
+            .iter()
+            .flatten()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+            .wrapping_sub(header[0x014E] as u16)
+            .wrapping_sub(header[0x014F] as u16);
+
+        CartridgeHeader {
+            title,
+            manufacturer_code,
+            cgb_flag,
+            cgb_support,
+            sgb_support: header[0x0146] == 0x03,
+            cartridge_type,
+            cartridge_kind: CartridgeKind::decode(cartridge_type).map(|kind| kind.to_string()),
+            rom_size_code,
+            rom_size: CartridgeHeader::rom_size(rom_size_code),
+            ram_size_code,
+            ram_size: CartridgeHeader::ram_size(ram_size_code),
+            destination: if header[0x014A] == 0x00 {
+                "Japan"
+            } else {
+                "Overseas"
+            },
+            old_licensee_code,
+            new_licensee_code,
+            licensee,
+            version: header[0x014C],
+            header_checksum,
+            header_checksum_valid: header_checksum == computed_header_checksum,
+            global_checksum,
+            global_checksum_valid: global_checksum == computed_global_checksum,
+            logo_valid: header[0x0104..0x0134] == NINTENDO_LOGO,
+            bank,
+        }
+    }
+
+    // MMM01 multicarts boot on their menu, which sits in the last 32 KiB of the ROM along with
+    // the header describing the cartridge. The first bank holds the header of the first game.
+    fn find_header_bank(banks: &[[u8; 0x4000]]) -> usize {
+        if banks.len() >= 4 {
+            let last_header = banks.len() - 2;
+            if let 0x0B..=0x0D = banks[last_header][0x0147] {
+                return last_header;
+            }
+        }
+        0
+    }
+
+    // ROM size in bytes declared by ROM[0x0148]
+    pub fn rom_size(code: u8) -> Option<usize> {
+        match code {
+            0x00..=0x08 => Some(0x8000 << code),
+            // Unofficial sizes listed in some documents
+            0x52 => Some(0x12_0000),
+            0x53 => Some(0x14_0000),
+            0x54 => Some(0x18_0000),
+            _ => None,
+        }
+    }
+
+    // RAM size in bytes declared by ROM[0x0149]
+    pub fn ram_size(code: u8) -> Option<usize> {
+        match code {
+            0x00 => Some(0),
+            0x01 => Some(0x800), // Unofficial 2 KiB size
+            0x02 => Some(0x2000),
+            0x03 => Some(0x8000),
+            0x04 => Some(0x2_0000),
+            0x05 => Some(0x1_0000),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = |size: Option<usize>| match size {
+            Some(size) => format!("{} KiB", size / 1024),
+            None => "unknown".to_string(),
+        };
+        let valid = |valid: bool| if valid { "OK" } else { "BAD" };
+
+        writeln!(f, "Title: {}", self.title)?;
+        if self.bank != 0 {
+            writeln!(f, "Header found in bank {:#04x}", self.bank)?;
+        }
+        if let Some(code) = &self.manufacturer_code {
+            writeln!(f, "Manufacturer: {}", code)?;
+        }
+        writeln!(f, "CGB: {} ({:#04x})", self.cgb_support, self.cgb_flag)?;
+        writeln!(f, "SGB: {}", if self.sgb_support { "yes" } else { "no" })?;
+        writeln!(
+            f,
+            "Type: {} ({:#04x})",
+            self.cartridge_kind.as_deref().unwrap_or("unknown"),
+            self.cartridge_type
+        )?;
+        writeln!(
+            f,
+            "ROM size: {} ({:#04x})",
+            size(self.rom_size),
+            self.rom_size_code
+        )?;
+        writeln!(
+            f,
+            "RAM size: {} ({:#04x})",
+            size(self.ram_size),
+            self.ram_size_code
+        )?;
+        writeln!(f, "Destination: {}", self.destination)?;
+        match &self.new_licensee_code {
+            Some(code) => writeln!(f, "Licensee: {} (new code {})", self.licensee, code)?,
+            None => writeln!(
+                f,
+                "Licensee: {} ({:#04x})",
+                self.licensee, self.old_licensee_code
+            )?,
+        }
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Nintendo logo: {}", valid(self.logo_valid))?;
+        writeln!(
+            f,
+            "Header checksum: {:#04x} {}",
+            self.header_checksum,
+            valid(self.header_checksum_valid)
+        )?;
+        write!(
+            f,
+            "Global checksum: {:#06x} {}",
+            self.global_checksum,
+            valid(self.global_checksum_valid)
+        )
+    }
+}
+
+fn new_licensee(code: &str) -> &'static str {
+    match code {
+        "00" => "None",
+        "01" => "Nintendo R&D1",
+        "08" => "Capcom",
+        "13" => "Electronic Arts",
+        "18" => "Hudson Soft",
+        "19" => "b-ai",
+        "20" => "KSS",
+        "22" => "POW",
+        "24" => "PCM Complete",
+        "25" => "San-X",
+        "28" => "Kemco Japan",
+        "29" => "SETA",
+        "30" => "Viacom",
+        "31" => "Nintendo",
+        "32" => "Bandai",
+        "33" => "Ocean/Acclaim",
+        "34" => "Konami",
+        "35" => "Hector",
+        "37" => "Taito",
+        "38" => "Hudson",
+        "39" => "Banpresto",
+        "41" => "Ubi Soft",
+        "42" => "Atlus",
+        "44" => "Malibu",
+        "46" => "Angel",
+        "47" => "Bullet-Proof",
+        "49" => "Irem",
+        "50" => "Absolute",
+        "51" => "Acclaim",
+        "52" => "Activision",
+        "53" => "American Sammy",
+        "54" => "Konami",
+        "55" => "Hi Tech Entertainment",
+        "56" => "LJN",
+        "57" => "Matchbox",
+        "58" => "Mattel",
+        "59" => "Milton Bradley",
+        "60" => "Titus",
+        "61" => "Virgin",
+        "64" => "LucasArts",
+        "67" => "Ocean",
+        "69" => "Electronic Arts",
+        "70" => "Infogrames",
+        "71" => "Interplay",
+        "72" => "Broderbund",
+        "73" => "Sculptured",
+        "75" => "SCi",
+        "78" => "THQ",
+        "79" => "Accolade",
+        "80" => "Misawa",
+        "83" => "Lozc",
+        "86" => "Tokuma Shoten Intermedia",
+        "87" => "Tsukuda Original",
+        "91" => "Chunsoft",
+        "92" => "Video System",
+        "93" => "Ocean/Acclaim",
+        "95" => "Varie",
+        "96" => "Yonezawa/S'pal",
+        "97" => "Kaneko",
+        "99" => "Pack-In-Soft",
+        "A4" => "Konami (Yu-Gi-Oh!)",
+        _ => "Unknown",
+    }
+}
+
+fn old_licensee(code: u8) -> &'static str {
+    match code {
+        0x00 => "None",
+        0x01 | 0x31 => "Nintendo",
+        0x08 | 0x38 => "Capcom",
+        0x09 => "Hot-B",
+        0x0A | 0xE0 => "Jaleco",
+        0x0B => "Coconuts Japan",
+        0x0C | 0x6E => "Elite Systems",
+        0x13 | 0x69 => "Electronic Arts",
+        0x18 => "Hudson Soft",
+        0x19 => "ITC Entertainment",
+        0x1A => "Yanoman",
+        0x1D => "Japan Clary",
+        0x1F | 0x4A | 0x61 => "Virgin Interactive",
+        0x24 => "PCM Complete",
+        0x25 => "San-X",
+        0x28 => "Kotobuki Systems",
+        0x29 => "SETA",
+        0x30 | 0x70 => "Infogrames",
+        0x32 | 0xA2 | 0xB2 => "Bandai",
+        0x34 | 0xA4 => "Konami",
+        0x35 => "HectorSoft",
+        0x39 | 0x9D | 0xD9 => "Banpresto",
+        0x3C => "Entertainment International",
+        0x3E => "Gremlin",
+        0x41 => "Ubi Soft",
+        0x42 | 0xEB => "Atlus",
+        0x44 | 0x4D => "Malibu",
+        0x46 | 0xCF => "Angel",
+        0x47 => "Spectrum Holobyte",
+        0x49 => "Irem",
+        0x4F => "U.S. Gold",
+        0x50 => "Absolute",
+        0x51 | 0xB0 => "Acclaim",
+        0x52 => "Activision",
+        0x53 => "American Sammy",
+        0x54 => "GameTek",
+        0x55 => "Park Place",
+        0x56 | 0xDB | 0xFF => "LJN",
+        0x57 => "Matchbox",
+        0x59 => "Milton Bradley",
+        0x5A => "Mindscape",
+        0x5B => "Romstar",
+        0x5C | 0xD6 => "Naxat Soft",
+        0x5D => "Tradewest",
+        0x60 => "Titus",
+        0x67 => "Ocean",
+        0x6F => "Electro Brain",
+        0x71 => "Interplay",
+        0x72 | 0xAA => "Broderbund",
+        0x73 => "Sculptured Software",
+        0x75 => "The Sales Curve",
+        0x78 => "THQ",
+        0x79 => "Accolade",
+        0x7A => "Triffix Entertainment",
+        0x7C => "Microprose",
+        0x7F | 0xC2 => "Kemco",
+        0x80 => "Misawa Entertainment",
+        0x83 => "Lozc",
+        0x86 | 0xC4 => "Tokuma Shoten Intermedia",
+        0x8B => "Bullet-Proof Software",
+        0x8C => "Vic Tokai",
+        0x8E => "Ape",
+        0x8F => "I'Max",
+        0x91 => "Chunsoft",
+        0x92 => "Video System",
+        0x93 => "Tsubaraya Productions",
+        0x95 | 0xE3 => "Varie",
+        0x96 => "Yonezawa/S'pal",
+        0x97 => "Kaneko",
+        0x99 => "Arc",
+        0x9A => "Nihon Bussan",
+        0x9B => "Tecmo",
+        0x9C => "Imagineer",
+        0x9F => "Nova",
+        0xA1 => "Hori Electric",
+        0xA6 => "Kawada",
+        0xA7 => "Takara",
+        0xA9 => "Technos Japan",
+        0xAC => "Toei Animation",
+        0xAD => "Toho",
+        0xAF => "Namco",
+        0xB1 => "ASCII or Nexsoft",
+        0xB4 => "Square Enix",
+        0xB6 => "HAL Laboratory",
+        0xB7 => "SNK",
+        0xB9 | 0xCE => "Pony Canyon",
+        0xBA => "Culture Brain",
+        0xBB => "Sunsoft",
+        0xBD => "Sony Imagesoft",
+        0xBF => "Sammy",
+        0xC0 | 0xD0 => "Taito",
+        0xC3 => "Squaresoft",
+        0xC5 => "Data East",
+        0xC6 => "Tonkinhouse",
+        0xC8 => "Koei",
+        0xC9 => "UFL",
+        0xCA => "Ultra",
+        0xCB => "Vap",
+        0xCC => "Use Corporation",
+        0xCD => "Meldac",
+        0xD1 => "Sofel",
+        0xD2 => "Quest",
+        0xD3 => "Sigma Enterprises",
+        0xD4 => "ASK Kodansha",
+        0xD7 => "Copya System",
+        0xDA => "Tomy",
+        0xDD => "NCS",
+        0xDE => "Human",
+        0xDF => "Altron",
+        0xE1 => "Towa Chiki",
+        0xE2 => "Yutaka",
+        0xE5 => "Epoch",
+        0xE7 => "Athena",
+        0xE8 => "Asmik Ace Entertainment",
+        0xE9 => "Natsume",
+        0xEA => "King Records",
+        0xEC => "Epic/Sony Records",
+        0xEE => "IGS",
+        0xF0 => "A Wave",
+        0xF3 => "Extreme Entertainment",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,251 @@
+use super::{Cartridge, CartridgeEvent};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Sources:
+// https://gbdev.io/pandocs/HuC1.html
+// https://gbdev.io/pandocs/HuC3.html
+//
+// Both Hudson mappers expose an IR transceiver at 0xA000~0xBFFF when selected through
+// 0x0000~0x1FFF. Reading it gives 0xC1 when light is received and 0xC0 otherwise,
+// bit 0 of a write drives the LED.
+
+// HuC3 modes, selected by writing to 0x0000~0x1FFF
+const HUC3_RAM_READ: u8 = 0x0;
+const HUC3_RAM_WRITE: u8 = 0xA;
+const HUC3_RTC_COMMAND: u8 = 0xB;
+const HUC3_RTC_RESPONSE: u8 = 0xC;
+const HUC3_RTC_SEMAPHORE: u8 = 0xD;
+const HUC3_IR: u8 = 0xE;
+
+// HuC3 RTC commands (upper nibble of the command byte)
+const RTC_READ: u8 = 0x1;
+const RTC_WRITE: u8 = 0x2;
+const RTC_WRITE_INC: u8 = 0x3;
+const RTC_ADDR_LOW: u8 = 0x4;
+const RTC_ADDR_HIGH: u8 = 0x5;
+const RTC_EXTENDED: u8 = 0x6;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+// Size of the RTC state stored after the RAM in the save file
+const HUC3_RTC_SAVE_SIZE: usize = 17;
+
+// The HuC3 RTC keeps the minute of the day (12 bits) and a day counter (16 bits).
+// They're accessed one nibble at a time through an address register:
+// 0x00~0x02 are the minutes, 0x03~0x06 the days and 0x58~0x5E the alarm.
+#[derive(Serialize, Deserialize)]
+pub struct Huc3 {
+    mode: u8,
+    command: u8,
+    response: u8,
+    address: u8,
+    minutes: u16,
+    days: u16,
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+    #[serde(skip, default = "Instant::now")]
+    last_tick: Instant,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+impl Huc3 {
+    pub fn new() -> Huc3 {
+        Huc3 {
+            mode: HUC3_RAM_READ,
+            command: 0,
+            response: 0,
+            address: 0,
+            minutes: 0,
+            days: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+            last_tick: Instant::now(),
+        }
+    }
+
+    // Advance the clock by the amount of whole minutes elapsed since the last update
+    fn update_clock(&mut self) {
+        let elapsed = self.last_tick.elapsed().as_secs() / 60;
+        if elapsed == 0 {
+            return;
+        }
+        self.last_tick += Duration::from_secs(elapsed * 60);
+
+        let total = self.minutes as u64 + elapsed;
+        self.minutes = (total % MINUTES_PER_DAY as u64) as u16;
+        self.days = self
+            .days
+            .wrapping_add((total / MINUTES_PER_DAY as u64) as u16);
+    }
+
+    // Clock registers followed by the time they were last updated, so the clock keeps running
+    // while the emulator is closed. All little endian.
+    pub fn rtc_data(&self) -> Vec<u8> {
+        let updated_at = unix_time().saturating_sub(self.last_tick.elapsed().as_secs());
+        let mut data = Vec::with_capacity(HUC3_RTC_SAVE_SIZE);
+        for register in &[self.minutes, self.days, self.alarm_minutes, self.alarm_days] {
+            data.extend_from_slice(&register.to_le_bytes());
+        }
+        data.push(self.alarm_enabled as u8);
+        data.extend_from_slice(&updated_at.to_le_bytes());
+        data
+    }
+
+    pub fn load_rtc_data(&mut self, data: &[u8]) {
+        if data.len() < HUC3_RTC_SAVE_SIZE {
+            return;
+        }
+        let register = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+        self.minutes = register(0) % MINUTES_PER_DAY;
+        self.days = register(1);
+        self.alarm_minutes = register(2);
+        self.alarm_days = register(3);
+        self.alarm_enabled = data[8] > 0;
+        let updated_at = u64::from_le_bytes(data[9..17].try_into().unwrap());
+        let elapsed = Duration::from_secs(unix_time().saturating_sub(updated_at));
+        self.last_tick = Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(Instant::now);
+    }
+
+    fn read_nibble(&self) -> u8 {
+        let shift = |index: u8| (index * 4) as u16;
+        let nibble = match self.address {
+            0x00..=0x02 => self.minutes >> shift(self.address),
+            0x03..=0x06 => self.days >> shift(self.address - 0x03),
+            0x58..=0x5A => self.alarm_minutes >> shift(self.address - 0x58),
+            0x5B..=0x5E => self.alarm_days >> shift(self.address - 0x5B),
+            _ => 0,
+        };
+        (nibble & 0x0F) as u8
+    }
+
+    fn write_nibble(&mut self, value: u8) {
+        let set = |reg: &mut u16, index: u8| {
+            let shift = (index * 4) as u16;
+            *reg = (*reg & !(0x0F << shift)) | ((value as u16 & 0x0F) << shift);
+        };
+        match self.address {
+            0x00..=0x02 => set(&mut self.minutes, self.address),
+            0x03..=0x06 => set(&mut self.days, self.address - 0x03),
+            0x58..=0x5A => set(&mut self.alarm_minutes, self.address - 0x58),
+            0x5B..=0x5E => set(&mut self.alarm_days, self.address - 0x5B),
+            _ => {}
+        }
+    }
+
+    fn execute(&mut self, command: u8) {
+        self.update_clock();
+        self.command = command;
+        let argument = command & 0x0F;
+
+        match command >> 4 {
+            RTC_READ => {
+                self.response = self.read_nibble();
+                self.address = self.address.wrapping_add(1);
+            }
+            RTC_WRITE => self.write_nibble(argument),
+            RTC_WRITE_INC => {
+                self.write_nibble(argument);
+                self.address = self.address.wrapping_add(1);
+            }
+            RTC_ADDR_LOW => self.address = (self.address & 0xF0) | argument,
+            RTC_ADDR_HIGH => self.address = (self.address & 0x0F) | (argument << 4),
+            RTC_EXTENDED => match argument {
+                // Latch the clock: make sure the minutes written by the game are in range
+                0x0 | 0x1 => self.minutes %= MINUTES_PER_DAY,
+                // Status, the RTC always reports being ready
+                0x2 => self.response = 0x1,
+                0xE => self.alarm_enabled = true,
+                0xF => self.alarm_enabled = false,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+impl Cartridge {
+    pub(super) fn read_ir(&self) -> u8 {
+        0xC0 | self.ir_light as u8
+    }
+
+    pub(super) fn write_ir(&mut self, data: u8) {
+        let led = data & 0b1 > 0;
+        if led != self.ir_led {
+            self.ir_led = led;
+            self.events.push(CartridgeEvent::Infrared(led));
+        }
+    }
+
+    pub(super) fn write_huc1(&mut self, addr: u16, data: u8) {
+        match addr {
+            // 0x0E maps the IR port at 0xA000~0xBFFF, anything else maps the RAM (always enabled)
+            0x0000..=0x1FFF => {
+                self.ir_mode = data == 0x0E;
+                self.ram_enabled = !self.ir_mode;
+            }
+            // 6-bit ROM bank number, unlike MBC1 bank 0 can be mapped at 0x4000
+            0x2000..=0x3FFF => self.active_bank = (data & 0b0011_1111) as u16,
+            // RAM bank number
+            0x4000..=0x5FFF => self.active_ram_bank = data & 0b0000_0011,
+            // No effect on HuC1
+            _ => {}
+        }
+    }
+
+    pub(super) fn write_huc3_control(&mut self, addr: u16, data: u8) {
+        match addr {
+            // Mode select
+            0x0000..=0x1FFF => {
+                self.huc3.mode = data & 0x0F;
+                self.ram_enabled =
+                    self.huc3.mode == HUC3_RAM_READ || self.huc3.mode == HUC3_RAM_WRITE;
+            }
+            // 7-bit ROM bank number
+            0x2000..=0x3FFF => {
+                self.active_bank = (data & 0b0111_1111) as u16;
+                if self.active_bank == 0 {
+                    self.active_bank = 1;
+                }
+            }
+            // RAM bank number
+            0x4000..=0x5FFF => self.active_ram_bank = data & 0b0000_0011,
+            // No effect on HuC3
+            _ => {}
+        }
+    }
+
+    pub(super) fn read_huc3(&self, addr: u16) -> u8 {
+        match self.huc3.mode {
+            HUC3_RAM_READ | HUC3_RAM_WRITE => self.read_ram_bank(addr),
+            // Upper nibble echoes the last command, lower nibble is the result
+            HUC3_RTC_COMMAND | HUC3_RTC_RESPONSE => {
+                0x80 | (self.huc3.command & 0x70) | self.huc3.response
+            }
+            // Commands complete instantly, the RTC is always ready
+            HUC3_RTC_SEMAPHORE => 0x01,
+            HUC3_IR => self.read_ir(),
+            _ => 0xFF,
+        }
+    }
+
+    pub(super) fn write_huc3(&mut self, addr: u16, data: u8) {
+        match self.huc3.mode {
+            HUC3_RAM_WRITE => self.write_ram_bank(addr, data),
+            HUC3_RTC_COMMAND => self.huc3.execute(data & 0x7F),
+            HUC3_IR => self.write_ir(data),
+            // RAM is read-only in mode 0x0, semaphore writes only request an update
+            _ => {}
+        }
+    }
+}
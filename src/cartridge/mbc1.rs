@@ -0,0 +1,63 @@
+use super::{Cartridge, MBC};
+use serde::{Deserialize, Serialize};
+
+// Sources:
+// https://gbdev.io/pandocs/MBC1.html
+// https://github.com/Gekkio/gb-ctr
+//
+// The 2-bit register at 0x4000~0x5FFF either extends the ROM bank number (mode 0) or, in mode 1,
+// also selects the RAM bank and the ROM bank mapped at 0x0000~0x3FFF.
+// MBC1M multicarts wire it 4 bits up instead of 5 and leave bit 4 of the 5-bit register unused,
+// so each 2-bit value selects one of the four 256 KiB games.
+#[derive(Serialize, Deserialize)]
+pub struct Mbc1 {
+    bank_low: u8,  // 5-bit register
+    bank_high: u8, // 2-bit register
+    mode: bool,    // Simple (false) / advanced (true) banking mode
+}
+
+impl Mbc1 {
+    pub fn new() -> Mbc1 {
+        Mbc1 {
+            bank_low: 1,
+            bank_high: 0,
+            mode: false,
+        }
+    }
+}
+
+impl Cartridge {
+    pub(super) fn write_mbc1(&mut self, addr: u16, data: u8) {
+        match addr {
+            // RAM enable
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            // Lower 5 bits of the ROM bank number
+            0x2000..=0x3FFF => {
+                self.mbc1.bank_low = data & 0b0001_1111;
+                // Bank switching bug, checked on all 5 bits even on MBC1M
+                if self.mbc1.bank_low == 0 {
+                    self.mbc1.bank_low = 1;
+                }
+            }
+            // Upper ROM bank bits / RAM bank number
+            0x4000..=0x5FFF => self.mbc1.bank_high = data & 0b0000_0011,
+            // Banking mode select
+            _ => self.mbc1.mode = data & 0b1 > 0,
+        }
+
+        let (shift, low_mask) = match self.kind.mbc {
+            Some(MBC::MBC1M) => (4, 0b0000_1111),
+            _ => (5, 0b0001_1111),
+        };
+        let upper = (self.mbc1.bank_high as u16) << shift;
+
+        self.active_bank = upper | (self.mbc1.bank_low & low_mask) as u16;
+        if self.mbc1.mode {
+            self.rom_bank_0 = upper;
+            self.active_ram_bank = self.mbc1.bank_high;
+        } else {
+            self.rom_bank_0 = 0;
+            self.active_ram_bank = 0;
+        }
+    }
+}
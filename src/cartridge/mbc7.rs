@@ -0,0 +1,248 @@
+use super::Cartridge;
+use serde::{Deserialize, Serialize};
+
+// Sources:
+// https://gbdev.io/pandocs/MBC7.html
+// https://ww1.microchip.com/downloads/en/DeviceDoc/21794G.pdf (93LC56)
+//
+// The registers at 0xA000~0xAFFF are only visible once both RAM enables are set
+// (0x0A at 0x0000~0x1FFF and 0x40 at 0x4000~0x5FFF). Bits 4-7 of the address select the register.
+
+// The accelerometer reads 0x81D0 when flat, and moves by about 0x70 per g
+const ACCEL_CENTER: f32 = 0x81D0 as f32;
+const ACCEL_ONE_G: f32 = 0x70 as f32;
+
+const EEPROM_WORDS: usize = 128;
+
+#[derive(Serialize, Deserialize)]
+enum EepromState {
+    Idle,                         // Waiting for a start bit
+    Command(u16, u8),             // Opcode and address bits received so far, bit count
+    Reading(u8, u16, u8),         // Address, word being shifted out, bits left
+    Writing(Option<u8>, u16, u8), // Address (None for WRAL), word being shifted in, bit count
+}
+
+// 93LC56 serial EEPROM in 16-bit mode: 128 words, commands are a start bit,
+// a 2-bit opcode and an 8-bit address (only the lower 7 bits are used)
+#[derive(Serialize, Deserialize)]
+pub struct Eeprom {
+    #[serde(with = "crate::serde_arrays")]
+    pub words: [u16; EEPROM_WORDS],
+    state: EepromState,
+    write_enabled: bool,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+}
+
+impl Eeprom {
+    fn new() -> Eeprom {
+        Eeprom {
+            words: [0xFFFF; EEPROM_WORDS],
+            state: EepromState::Idle,
+            write_enabled: false,
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        ((self.cs as u8) << 7) | ((self.clk as u8) << 6) | ((self.di as u8) << 1) | self.dout as u8
+    }
+
+    fn write(&mut self, data: u8) {
+        let clk = data & 0b0100_0000 > 0;
+        let rising_edge = clk && !self.clk;
+        self.cs = data & 0b1000_0000 > 0;
+        self.clk = clk;
+        self.di = data & 0b0000_0010 > 0;
+
+        if !self.cs {
+            // Deselecting aborts any command in progress
+            self.state = EepromState::Idle;
+            return;
+        }
+        if rising_edge {
+            self.clock(self.di);
+        }
+    }
+
+    // One bit shifted in on a rising edge of CLK
+    fn clock(&mut self, bit: bool) {
+        self.state = match self.state {
+            EepromState::Idle => {
+                if bit {
+                    EepromState::Command(0, 0)
+                } else {
+                    EepromState::Idle
+                }
+            }
+            EepromState::Command(value, count) => {
+                let value = (value << 1) | bit as u16;
+                if count + 1 < 10 {
+                    EepromState::Command(value, count + 1)
+                } else {
+                    self.decode(value)
+                }
+            }
+            EepromState::Reading(addr, word, bits_left) => {
+                self.dout = word & 0x8000 > 0;
+                if bits_left > 1 {
+                    EepromState::Reading(addr, word << 1, bits_left - 1)
+                } else {
+                    // Sequential read, keeps going with the next word
+                    let next = (addr + 1) % EEPROM_WORDS as u8;
+                    EepromState::Reading(next, self.words[next as usize], 16)
+                }
+            }
+            EepromState::Writing(addr, word, count) => {
+                let word = (word << 1) | bit as u16;
+                if count + 1 < 16 {
+                    EepromState::Writing(addr, word, count + 1)
+                } else {
+                    if self.write_enabled {
+                        match addr {
+                            Some(addr) => self.words[addr as usize] = word,
+                            None => self.words = [word; EEPROM_WORDS],
+                        }
+                    }
+                    // Writes complete instantly, report ready
+                    self.dout = true;
+                    EepromState::Idle
+                }
+            }
+        }
+    }
+
+    fn decode(&mut self, command: u16) -> EepromState {
+        let addr = (command & 0x7F) as u8;
+        match command >> 8 {
+            // READ, a dummy 0 bit comes first
+            0b10 => {
+                self.dout = false;
+                EepromState::Reading(addr, self.words[addr as usize], 16)
+            }
+            // WRITE
+            0b01 => EepromState::Writing(Some(addr), 0, 0),
+            // ERASE
+            0b11 => {
+                if self.write_enabled {
+                    self.words[addr as usize] = 0xFFFF;
+                }
+                self.dout = true;
+                EepromState::Idle
+            }
+            // Extended commands, selected by the upper address bits
+            _ => match (command >> 6) & 0b11 {
+                // EWDS
+                0b00 => {
+                    self.write_enabled = false;
+                    EepromState::Idle
+                }
+                // WRAL
+                0b01 => EepromState::Writing(None, 0, 0),
+                // ERAL
+                0b10 => {
+                    if self.write_enabled {
+                        self.words = [0xFFFF; EEPROM_WORDS];
+                    }
+                    self.dout = true;
+                    EepromState::Idle
+                }
+                // EWEN
+                _ => {
+                    self.write_enabled = true;
+                    EepromState::Idle
+                }
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Mbc7 {
+    pub eeprom: Eeprom,
+    ram_enabled_2: bool,
+    // Current tilt in g, fed by the controls
+    tilt_x: f32,
+    tilt_y: f32,
+    latch_x: u16,
+    latch_y: u16,
+    latch_ready: bool, // Set after an erase, cleared after latching
+}
+
+impl Mbc7 {
+    pub fn new() -> Mbc7 {
+        Mbc7 {
+            eeprom: Eeprom::new(),
+            ram_enabled_2: false,
+            tilt_x: 0.0,
+            tilt_y: 0.0,
+            latch_x: 0x8000,
+            latch_y: 0x8000,
+            latch_ready: false,
+        }
+    }
+}
+
+impl Cartridge {
+    pub fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.mbc7.tilt_x = x;
+        self.mbc7.tilt_y = y;
+    }
+
+    pub(super) fn write_mbc7_control(&mut self, addr: u16, data: u8) {
+        match addr {
+            // RAM enable 1
+            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+            // ROM bank number
+            0x2000..=0x3FFF => self.active_bank = data as u16,
+            // RAM enable 2
+            0x4000..=0x5FFF => self.mbc7.ram_enabled_2 = data == 0x40,
+            // No effect
+            _ => {}
+        }
+    }
+
+    pub(super) fn read_mbc7(&self, addr: u16) -> u8 {
+        let mbc7 = &self.mbc7;
+        if !self.ram_enabled || !mbc7.ram_enabled_2 || addr >= 0xB000 {
+            return 0xFF;
+        }
+        match (addr >> 4) & 0x0F {
+            0x2 => mbc7.latch_x as u8,
+            0x3 => (mbc7.latch_x >> 8) as u8,
+            0x4 => mbc7.latch_y as u8,
+            0x5 => (mbc7.latch_y >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => mbc7.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    pub(super) fn write_mbc7(&mut self, addr: u16, data: u8) {
+        let mbc7 = &mut self.mbc7;
+        if !self.ram_enabled || !mbc7.ram_enabled_2 || addr >= 0xB000 {
+            return;
+        }
+        match (addr >> 4) & 0x0F {
+            // Erase the latched values
+            0x0 if data == 0x55 => {
+                mbc7.latch_x = 0x8000;
+                mbc7.latch_y = 0x8000;
+                mbc7.latch_ready = true;
+            }
+            // Latch the accelerometer
+            0x1 if data == 0xAA && mbc7.latch_ready => {
+                mbc7.latch_x = (ACCEL_CENTER + mbc7.tilt_x * ACCEL_ONE_G) as u16;
+                mbc7.latch_y = (ACCEL_CENTER + mbc7.tilt_y * ACCEL_ONE_G) as u16;
+                mbc7.latch_ready = false;
+            }
+            0x8 => mbc7.eeprom.write(data),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use super::Cartridge;
+use serde::{Deserialize, Serialize};
+
+// Sources:
+// https://gbdev.io/pandocs/MMM01.html
+// https://wiki.tauwasser.eu/view/MMM01
+//
+// The MMM01 starts "unmapped": the last 32 KiB of the ROM (the menu) is visible at 0x0000~0x7FFF
+// and the menu is free to configure the outer bank bits and the masks.
+// Once the map enable bit is written, those settings are frozen and the selected game sees an
+// MBC1-like mapper restricted to its own slice of the ROM and RAM.
+#[derive(Serialize, Deserialize)]
+pub struct Mmm01 {
+    mapped: bool,
+    rom_bank: u16, // 9-bit ROM bank number
+    rom_mask: u16, // Bits 1-4 of the ROM bank number frozen once mapped
+    ram_bank: u8,  // 4-bit RAM bank number
+    ram_mask: u8,  // Bits 0-1 of the RAM bank number frozen once mapped
+    mode_locked: bool,
+    mbc1_mode: bool,
+}
+
+impl Mmm01 {
+    pub fn new() -> Mmm01 {
+        Mmm01 {
+            mapped: false,
+            rom_bank: 0,
+            rom_mask: 0,
+            ram_bank: 0,
+            ram_mask: 0,
+            mode_locked: false,
+            mbc1_mode: false,
+        }
+    }
+}
+
+impl Cartridge {
+    pub(super) fn write_mmm01(&mut self, addr: u16, data: u8) {
+        let mapper = &mut self.mmm01;
+        match addr {
+            // RAM enable, plus RAM bank mask and map enable while unmapped
+            0x0000..=0x1FFF => {
+                self.ram_enabled = data & 0x0F == 0x0A;
+                if !mapper.mapped {
+                    mapper.ram_mask = (data >> 4) & 0b11;
+                    mapper.mapped = data & 0b0100_0000 > 0;
+                }
+            }
+            // ROM bank number: bits 0-4 (minus the masked ones) once mapped, bits 0-6 otherwise
+            0x2000..=0x3FFF => {
+                let writable = if mapper.mapped {
+                    0b0001_1111 & !mapper.rom_mask
+                } else {
+                    0b0111_1111
+                };
+                mapper.rom_bank = (mapper.rom_bank & !writable) | (data as u16 & writable);
+            }
+            // RAM bank number, plus ROM bank bits 7-8 and mode lock while unmapped
+            0x4000..=0x5FFF => {
+                if mapper.mapped {
+                    let writable = 0b11 & !mapper.ram_mask;
+                    mapper.ram_bank = (mapper.ram_bank & !writable) | (data & writable);
+                } else {
+                    mapper.ram_bank = data & 0b1111;
+                    mapper.rom_bank =
+                        (mapper.rom_bank & 0x07F) | (((data >> 4) & 0b11) as u16) << 7;
+                    mapper.mode_locked = data & 0b0100_0000 > 0;
+                }
+            }
+            // MBC1 mode select, plus ROM bank mask while unmapped
+            _ => {
+                if !mapper.mapped {
+                    mapper.rom_mask = (((data >> 2) & 0b1111) as u16) << 1;
+                }
+                if !mapper.mode_locked {
+                    mapper.mbc1_mode = data & 0b1 > 0;
+                }
+            }
+        }
+        self.update_mmm01_banks();
+    }
+
+    pub(super) fn update_mmm01_banks(&mut self) {
+        let mapper = &self.mmm01;
+
+        if !mapper.mapped {
+            // The upper address lines are pulled high, exposing the menu at the end of the ROM
+            self.rom_bank_0 = 0x1FE;
+            self.active_bank = 0x1FF;
+            self.active_ram_bank = mapper.ram_bank;
+            return;
+        }
+
+        // Bits the game is allowed to switch, everything else selects the game's slice
+        let game_bits = 0b0001_1111 & !mapper.rom_mask;
+        let mut bank = mapper.rom_bank;
+        // Same quirk as MBC1: bank 0 of the slice can't be mapped at 0x4000
+        if bank & game_bits == 0 {
+            bank |= 0b1;
+        }
+        self.rom_bank_0 = mapper.rom_bank & !game_bits;
+        self.active_bank = bank;
+        self.active_ram_bank = if mapper.mbc1_mode {
+            mapper.ram_bank
+        } else {
+            mapper.ram_bank & !(0b11 & !mapper.ram_mask)
+        };
+    }
+}
@@ -0,0 +1,468 @@
+use crate::{file_io, Config};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::mem;
+
+pub use header::CartridgeHeader;
+
+mod camera;
+mod header;
+mod huc;
+mod mbc1;
+mod mbc7;
+mod mmm01;
+
+// Sources:
+// https://retrocomputing.stackexchange.com/questions/11732/how-does-the-gameboys-memory-bank-switching-work
+// https://gbdev.io/pandocs/Memory_Map.html
+// https://b13rg.github.io/Gameboy-MBC-Analysis/
+pub enum MBC {
+    MBC1,
+    MBC1M,
+    MBC2,
+    MBC3,
+    MBC5,
+    MBC7,
+    MMM01,
+    HuC1,
+    HuC3,
+    PocketCamera,
+}
+
+impl fmt::Display for MBC {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MBC::MBC1 => write!(f, "MBC1"),
+            MBC::MBC1M => write!(f, "MBC1M"),
+            MBC::MBC2 => write!(f, "MBC2"),
+            MBC::MBC3 => write!(f, "MBC3"),
+            MBC::MBC5 => write!(f, "MBC5"),
+            MBC::MBC7 => write!(f, "MBC7"),
+            MBC::MMM01 => write!(f, "MMM01"),
+            MBC::HuC1 => write!(f, "HuC1"),
+            MBC::HuC3 => write!(f, "HuC3"),
+            MBC::PocketCamera => write!(f, "POCKET CAMERA"),
+        }
+    }
+}
+
+// Logo checked by the boot ROM, found at 0x0104~0x0133 of every valid header
+pub const NINTENDO_LOGO: [u8; 0x30] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// True if another game header starts on one of the 256 KiB boundaries after the first one
+pub fn has_multicart_logos(banks: &[[u8; 0x4000]]) -> bool {
+    (0x10..banks.len())
+        .step_by(0x10)
+        .any(|bank| banks[bank][0x0104..0x0134] == NINTENDO_LOGO)
+}
+
+#[derive(Default)]
+pub struct CartridgeKind {
+    mbc: Option<MBC>,
+    ram: bool,
+    battery: bool,
+    timer: bool,
+    rumble: bool,
+}
+
+impl fmt::Display for CartridgeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}{}",
+            if let Some(mbc) = &self.mbc {
+                mbc.to_string()
+            } else {
+                "ROM".to_string()
+            },
+            if self.timer { " + TIMER" } else { "" },
+            if self.rumble { " + RUMBLE" } else { "" },
+            if self.ram { " + RAM" } else { "" },
+            if self.battery { " + BATTERY" } else { "" },
+        )
+    }
+}
+
+impl CartridgeKind {
+    // Create one with info from the dedicated byte
+    fn from(code: u8) -> CartridgeKind {
+        CartridgeKind::decode(code).expect("Invalid cartridge type in ROM[0x0147]")
+    }
+
+    // Same as from(), None for unknown cartridge types
+    pub fn decode(code: u8) -> Option<CartridgeKind> {
+        let kind = match code {
+            0x00 | 0x08..=0x09 => CartridgeKind {
+                mbc: None,
+                ram: code >= 0x08,
+                battery: code == 0x09,
+                timer: false,
+                rumble: false,
+            },
+            0x01..=0x03 => CartridgeKind {
+                mbc: Some(MBC::MBC1),
+                ram: code >= 0x02,
+                battery: code == 0x03,
+                timer: false,
+                rumble: false,
+            },
+            0x05..=0x06 => CartridgeKind {
+                mbc: Some(MBC::MBC2),
+                ram: false,
+                battery: code == 0x06,
+                timer: false,
+                rumble: false,
+            },
+            0x0B..=0x0D => CartridgeKind {
+                mbc: Some(MBC::MMM01),
+                ram: code >= 0x0C,
+                battery: code == 0x0D,
+                timer: false,
+                rumble: false,
+            },
+            0x0F..=0x13 => CartridgeKind {
+                mbc: Some(MBC::MBC3),
+                ram: code == 0x10 || code >= 0x12,
+                battery: code <= 0x10 || code == 0x13,
+                timer: code <= 0x10,
+                rumble: false,
+            },
+            0x19..=0x1E => CartridgeKind {
+                mbc: Some(MBC::MBC5),
+                ram: code != 0x19 && code != 0x1C,
+                battery: code == 0x1B || code == 0x1E,
+                timer: false,
+                rumble: code >= 0x1C,
+            },
+            // The EEPROM counts as RAM, the accelerometer isn't part of the kind
+            0x22 => CartridgeKind {
+                mbc: Some(MBC::MBC7),
+                ram: true,
+                battery: true,
+                timer: false,
+                rumble: false,
+            },
+            0xFC => CartridgeKind {
+                mbc: Some(MBC::PocketCamera),
+                ram: true,
+                battery: true,
+                timer: false,
+                rumble: false,
+            },
+            0xFE => CartridgeKind {
+                mbc: Some(MBC::HuC3),
+                ram: true,
+                battery: true,
+                timer: true,
+                rumble: false,
+            },
+            0xFF => CartridgeKind {
+                mbc: Some(MBC::HuC1),
+                ram: true,
+                battery: true,
+                timer: false,
+                rumble: false,
+            },
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+// Events raised by the cartridge hardware that the frontend may want to act on
+pub enum CartridgeEvent {
+    Rumble(bool),   // Motor on (true) / off (false)
+    Infrared(bool), // IR LED on (true) / off (false)
+}
+
+// Savestates hold the RAM and the mapper state, the ROM and what is read from it stay loaded
+#[derive(Serialize, Deserialize)]
+pub struct Cartridge {
+    #[serde(skip)]
+    pub banks: Vec<[u8; 0x4000]>,
+    #[serde(skip)]
+    pub header: CartridgeHeader,
+    #[serde(with = "crate::serde_arrays::vec")]
+    pub ram_banks: Vec<[u8; 0x2000]>,
+    #[serde(skip)]
+    pub kind: CartridgeKind,
+    #[serde(skip)]
+    pub events: Vec<CartridgeEvent>,
+    // Light seen by the IR receiver of HuC1/HuC3 cartridges
+    #[serde(skip)]
+    pub ir_light: bool,
+    rom_bank_0: u16,
+    active_bank: u16,
+    active_ram_bank: u8,
+    ram_enabled: bool,
+    rumble_on: bool,
+    ir_mode: bool,
+    ir_led: bool,
+    mbc1: mbc1::Mbc1,
+    mbc7: mbc7::Mbc7,
+    mmm01: mmm01::Mmm01,
+    huc3: huc::Huc3,
+    camera: Option<camera::Camera>,
+}
+
+impl Cartridge {
+    pub fn new(config: &Config) -> Result<Cartridge, String> {
+        let banks = file_io::load_rom(config)?;
+        let header = CartridgeHeader::from_rom(&banks);
+        let kind = CartridgeKind::from(header.cartridge_type);
+        // The unofficial 2 KiB size still gets a full bank
+        let ram_banks = vec![[0; 0x2000]; header.ram_size.unwrap_or(0).div_ceil(0x2000)];
+        let camera = match kind.mbc {
+            // Captured pictures are written to the RAM
+            Some(MBC::PocketCamera) if ram_banks.is_empty() => {
+                return Err("Pocket Camera cartridge without RAM".to_string())
+            }
+            Some(MBC::PocketCamera) => Some(camera::Camera::new(camera::ImageSource::new(
+                config.camera_source,
+            )?)),
+            _ => None,
+        };
+        let mut cartridge = Cartridge {
+            banks,
+            ram_banks,
+            header,
+            kind,
+            events: Vec::new(),
+            ir_light: false,
+            rom_bank_0: 0,
+            active_bank: 1,
+            active_ram_bank: 0,
+            ram_enabled: false,
+            rumble_on: false,
+            ir_mode: false,
+            ir_led: false,
+            mbc1: mbc1::Mbc1::new(),
+            mbc7: mbc7::Mbc7::new(),
+            mmm01: mmm01::Mmm01::new(),
+            huc3: huc::Huc3::new(),
+            camera,
+        };
+        match cartridge.kind.mbc {
+            Some(MBC::MBC1) if cartridge.is_multicart() => cartridge.kind.mbc = Some(MBC::MBC1M),
+            Some(MBC::MMM01) => cartridge.update_mmm01_banks(),
+            _ => {}
+        }
+        if cartridge.kind.battery {
+            if let Some(data) = file_io::load_save(config) {
+                cartridge.load_save_data(&data);
+            }
+        }
+        Ok(cartridge)
+    }
+
+    pub fn load_state(&mut self, saved: Cartridge) {
+        let camera = match (self.camera.take(), saved.camera) {
+            (Some(mut camera), Some(saved)) => {
+                camera.load_state(saved);
+                Some(camera)
+            }
+            (camera, _) => camera,
+        };
+        *self = Cartridge {
+            banks: mem::take(&mut self.banks),
+            header: mem::take(&mut self.header),
+            kind: mem::take(&mut self.kind),
+            events: mem::take(&mut self.events),
+            ir_light: self.ir_light,
+            camera,
+            ..saved
+        };
+    }
+
+    // Content of the battery backed memory, as stored in the save file
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        if !self.kind.battery {
+            return None;
+        }
+        let mut data: Vec<u8> = match self.kind.mbc {
+            Some(MBC::MBC7) => self
+                .mbc7
+                .eeprom
+                .words
+                .iter()
+                .flat_map(|word| word.to_le_bytes().to_vec())
+                .collect(),
+            _ => self
+                .ram_banks
+                .iter()
+                .flat_map(|bank| bank.to_vec())
+                .collect(),
+        };
+        // The HuC3 clock keeps running with the battery
+        if let Some(MBC::HuC3) = self.kind.mbc {
+            data.extend(self.huc3.rtc_data());
+        }
+        if data.is_empty() {
+            None
+        } else {
+            Some(data)
+        }
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        match self.kind.mbc {
+            Some(MBC::MBC7) => {
+                for (word, bytes) in self.mbc7.eeprom.words.iter_mut().zip(data.chunks_exact(2)) {
+                    *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            _ => {
+                for (bank, bytes) in self.ram_banks.iter_mut().zip(data.chunks(0x2000)) {
+                    bank[..bytes.len()].copy_from_slice(bytes);
+                }
+                // The HuC3 clock follows the RAM, older saves don't have it
+                let ram_size = self.ram_banks.len() * 0x2000;
+                if let (Some(MBC::HuC3), Some(rtc)) = (&self.kind.mbc, data.get(ram_size..)) {
+                    self.huc3.load_rtc_data(rtc);
+                }
+            }
+        }
+    }
+
+    // MBC1M compilations are 1 MiB ROMs made of 256 KiB games, each starting with its own header.
+    // Nothing in the header tells them apart from a regular MBC1 cartridge, so look for the
+    // Nintendo logo at the start of the following games.
+    fn is_multicart(&self) -> bool {
+        self.banks.len() == 64 && has_multicart_logos(&self.banks)
+    }
+
+    // Bank numbers wider than the ROM are cut down, as the upper address lines aren't connected
+    fn rom_bank_index(&self, bank: u16) -> usize {
+        let index = bank as usize & (self.banks.len().next_power_of_two() - 1);
+        index % self.banks.len()
+    }
+
+    // Bank mapped at a ROM address
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x0000..=0x3FFF => self.rom_bank_index(self.rom_bank_0),
+            _ => self.rom_bank_index(self.active_bank),
+        }
+    }
+
+    pub fn read_rom(&self, addr: u16) -> u8 {
+        self.banks[self.rom_bank(addr)][(addr & 0x3FFF) as usize]
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        match self.kind.mbc {
+            Some(MBC::HuC1) if self.ir_mode => return self.read_ir(),
+            Some(MBC::HuC3) => return self.read_huc3(addr),
+            Some(MBC::PocketCamera) => return self.read_camera(addr),
+            Some(MBC::MBC7) => return self.read_mbc7(addr),
+            _ => {}
+        }
+        self.read_ram_bank(addr)
+    }
+
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        match self.kind.mbc {
+            Some(MBC::HuC1) if self.ir_mode => return self.write_ir(data),
+            Some(MBC::HuC3) => return self.write_huc3(addr, data),
+            Some(MBC::PocketCamera) => return self.write_camera(addr, data),
+            Some(MBC::MBC7) => return self.write_mbc7(addr, data),
+            _ => {}
+        }
+        self.write_ram_bank(addr, data)
+    }
+
+    // Advance the hardware living on the cartridge
+    pub fn update(&mut self, ticks: u8) {
+        if let Some(MBC::PocketCamera) = self.kind.mbc {
+            self.update_camera(ticks);
+        }
+    }
+
+    fn read_ram_bank(&self, addr: u16) -> u8 {
+        if !self.ram_enabled || self.ram_banks.is_empty() {
+            return 0xFF;
+        }
+        let bank = self.active_ram_bank as usize % self.ram_banks.len();
+        self.ram_banks[bank][(addr & 0x1FFF) as usize]
+    }
+
+    fn write_ram_bank(&mut self, addr: u16, data: u8) {
+        if !self.ram_enabled || self.ram_banks.is_empty() {
+            return;
+        }
+        let bank = self.active_ram_bank as usize % self.ram_banks.len();
+        self.ram_banks[bank][(addr & 0x1FFF) as usize] = data;
+    }
+
+    // Writes to 0x0000~0x7FFF, which target the MBC registers
+    pub fn write_control(&mut self, addr: u16, data: u8) {
+        match self.kind.mbc {
+            Some(MBC::MBC1) | Some(MBC::MBC1M) => return self.write_mbc1(addr, data),
+            Some(MBC::MBC5) => return self.write_mbc5(addr, data),
+            Some(MBC::MMM01) => return self.write_mmm01(addr, data),
+            Some(MBC::HuC1) => return self.write_huc1(addr, data),
+            Some(MBC::HuC3) => return self.write_huc3_control(addr, data),
+            Some(MBC::PocketCamera) => return self.write_camera_control(addr, data),
+            Some(MBC::MBC7) => return self.write_mbc7_control(addr, data),
+            _ => {}
+        }
+
+        match addr {
+            // RAM enable
+            0x0000..=0x1FFF if self.kind.ram => {
+                self.ram_enabled = data & 0x0F == 0x0A;
+            }
+            // Select lower 5 bits of the bank number
+            0x2000..=0x3FFF if self.kind.mbc.is_some() => {
+                let mut data_lower_5 = (data & 0b0001_1111) as u16;
+                // Bank switching bug
+                if data_lower_5 == 0 {
+                    data_lower_5 += 1;
+                }
+                self.active_bank = (self.active_bank & 0b1110_0000) | data_lower_5;
+            }
+            // Select bits 6 and 7 of the bank number
+            0x4000..=0x5FFF => {
+                // TODO: behavior when in RAM mode
+                self.active_bank =
+                    (self.active_bank & 0b1001_1111) | (((data & 0b0000_0011) as u16) << 5);
+            }
+            // MBC3 latches its clock here, which isn't emulated. MBC2 has nothing mapped.
+            _ => {}
+        }
+    }
+
+    // MBC5 has a 9-bit ROM bank number (bank 0 can be mapped at 0x4000) and up to 16 RAM banks.
+    // On rumble cartridges, bit 3 of the RAM bank register drives the motor instead.
+    fn write_mbc5(&mut self, addr: u16, data: u8) {
+        match addr {
+            // RAM enable, only 0x0A enables it on MBC5
+            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+            // Lower 8 bits of the ROM bank number
+            0x2000..=0x2FFF => self.active_bank = (self.active_bank & 0x100) | data as u16,
+            // 9th bit of the ROM bank number
+            0x3000..=0x3FFF => {
+                self.active_bank = (self.active_bank & 0x0FF) | (((data & 0b1) as u16) << 8)
+            }
+            // RAM bank number (and rumble motor)
+            0x4000..=0x5FFF => {
+                if self.kind.rumble {
+                    self.active_ram_bank = data & 0b0000_0111;
+                    let motor = data & 0b0000_1000 > 0;
+                    if motor != self.rumble_on {
+                        self.rumble_on = motor;
+                        self.events.push(CartridgeEvent::Rumble(motor));
+                    }
+                } else {
+                    self.active_ram_bank = data & 0b0000_1111;
+                }
+            }
+            // Nothing is mapped here on MBC5
+            _ => {}
+        }
+    }
+}
@@ -2,8 +2,12 @@ use crate::gui::Gui;
 use crate::hardware::Cpu;
 use crate::memory::Memory;
 use crate::Config;
+use sdl2::controller::Axis;
 use sdl2::keyboard::*;
 
+// Stick values below this are ignored when tilting with a gamepad
+const STICK_DEAD_ZONE: i16 = 4000;
+
 pub struct Controls {
     pub up: u8,
     pub down: u8,
@@ -13,6 +17,9 @@ pub struct Controls {
     pub b: u8,
     pub select: u8,
     pub start: u8,
+    // Tilt in g for cartridges with an accelerometer, positive is right / down
+    pub tilt_x: f32,
+    pub tilt_y: f32,
 }
 
 impl Controls {
@@ -31,6 +38,8 @@ impl Controls {
         self.b = 1;
         self.select = 1;
         self.start = 1;
+        self.tilt_x = 0.0;
+        self.tilt_y = 0.0;
         let iterator = gui.events.keyboard_state();
         for scancode in iterator.pressed_scancodes() {
             match scancode {
@@ -66,15 +75,52 @@ impl Controls {
                     //println!("select");
                     self.select = 0;
                 }
+                // Tilt
+                Scancode::J => self.tilt_x -= 1.0,
+                Scancode::L => self.tilt_x += 1.0,
+                Scancode::I => self.tilt_y -= 1.0,
+                Scancode::K => self.tilt_y += 1.0,
                 Scancode::F2 => {
-                    crate::file_io::create_savestate(config, cpu, &mem.ram);
+                    crate::file_io::create_savestate(config, cpu, mem);
                 }
                 Scancode::F3 => {
-                    crate::file_io::load_savestate(config, cpu, &mut mem.ram);
+                    crate::file_io::load_savestate(config, cpu, mem);
                 }
                 _ => {}
             }
         }
+
+        let mut toggle_scope = false;
+        for keycode in &gui.pressed_keys {
+            match *keycode {
+                Keycode::F4 => toggle_recording(config, mem),
+                // Sound channels: 1~4 mute them, F5~F8 solo them
+                Keycode::Num1 => mem.apu.toggle_mute(0),
+                Keycode::Num2 => mem.apu.toggle_mute(1),
+                Keycode::Num3 => mem.apu.toggle_mute(2),
+                Keycode::Num4 => mem.apu.toggle_mute(3),
+                Keycode::F5 => mem.apu.solo(0),
+                Keycode::F6 => mem.apu.solo(1),
+                Keycode::F7 => mem.apu.solo(2),
+                Keycode::F8 => mem.apu.solo(3),
+                Keycode::F9 => toggle_scope = true,
+                _ => {}
+            }
+        }
+        if toggle_scope {
+            gui.toggle_scope();
+        }
+
+        // The left stick takes over the keyboard when pushed
+        if let Some(controller) = &gui.controller {
+            let (x, y) = (controller.axis(Axis::LeftX), controller.axis(Axis::LeftY));
+            let pushed = |value: i16| !(-STICK_DEAD_ZONE..=STICK_DEAD_ZONE).contains(&value);
+            if pushed(x) || pushed(y) {
+                self.tilt_x = x as f32 / i16::MAX as f32;
+                self.tilt_y = y as f32 / i16::MAX as f32;
+            }
+        }
+        mem.cartridge.set_accelerometer(self.tilt_x, self.tilt_y);
     }
 
     pub fn update_ram(&self, mem: &mut Memory) {
@@ -86,6 +132,22 @@ impl Controls {
             //buttons
             n = 0b11010000 | (self.start << 3) | (self.select << 2) | (self.b << 1) | (self.a);
         }
-        mem.write(0xff00, n);
+        // The button bits are read only for the CPU
+        mem.ram[0x7F00] = n;
+    }
+}
+
+fn toggle_recording(config: &Config, mem: &mut Memory) {
+    if mem.apu.is_recording() {
+        match mem.apu.stop_recording() {
+            Ok(()) => println!("Stopped recording audio"),
+            Err(e) => eprintln!("Warning: unable to finish the recording ({})", e),
+        }
+    } else {
+        let path = crate::file_io::get_recording_path(config);
+        match mem.apu.start_recording(&path, config.record_channels) {
+            Ok(()) => println!("Recording audio to {}", path.display()),
+            Err(e) => eprintln!("Warning: {}", e),
+        }
     }
 }
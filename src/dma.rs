@@ -1,13 +1,87 @@
 use crate::memory::Memory;
+use serde::{Deserialize, Serialize};
 
-pub fn update_dma(mem: &mut Memory) {
-    if mem.read(0xff46) != 0 {
-        //println!("/!\\ DMA HAS OCCURRED");
-        let source: u16 = (mem.read(0xff46) as u16) << 8;
-        let dest: u16 = 0xFE00;
-        for i in 0..0xA0 {
-            mem.write(dest + i, mem.read(source + i));
+// Sources:
+// https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+// https://github.com/Gekkio/mooneye-test-suite (oam_dma tests)
+//
+// Writing to 0xFF46 copies 0xXX00~0xXX9F to OAM, one byte per M-cycle after a cycle of setup.
+// While it runs, the CPU shares its bus with the transfer: it reads the byte being copied instead
+// of what it asked for, OAM reads as 0xFF, and only HRAM and the I/O registers work normally.
+
+const OAM: u16 = 0xFE00;
+const LENGTH: u16 = 0xA0;
+
+#[derive(PartialEq)]
+enum Bus {
+    External, // Cartridge and work RAM
+    Video,    // VRAM
+    Internal, // OAM, I/O registers and HRAM
+}
+
+fn bus(addr: u16) -> Bus {
+    match addr {
+        0x8000..=0x9FFF => Bus::Video,
+        0xFE00..=0xFFFF => Bus::Internal,
+        _ => Bus::External,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Dma {
+    source: u16,
+    index: u16,
+    active: bool,
+    requested: Option<u8>, // Page written to 0xFF46, starts after the setup cycle
+    last_byte: u8,         // What the CPU sees on a conflicting bus
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            source: 0,
+            index: 0,
+            active: false,
+            requested: None,
+            last_byte: 0xFF,
+        }
+    }
+
+    // Writing during a transfer restarts it, the old one keeps going until the new one starts
+    pub fn request(&mut self, page: u8) {
+        self.requested = Some(page);
+    }
+
+    // What the CPU reads instead of addr, None if the transfer doesn't get in the way
+    pub fn conflict(&self, addr: u16) -> Option<u8> {
+        if !self.active {
+            return None;
+        }
+        match addr {
+            0xFE00..=0xFEFF => Some(0xFF),
+            0xFF00..=0xFFFF => None,
+            _ if bus(addr) == bus(self.source) => Some(self.last_byte),
+            _ => None,
+        }
+    }
+}
+
+pub fn update_dma(mem: &mut Memory, cycles: u8) {
+    for _ in 0..cycles {
+        if mem.dma.active {
+            let value = mem.read_bus(mem.dma.source + mem.dma.index);
+            mem.ram[((OAM + mem.dma.index) & 0x7FFF) as usize] = value;
+            mem.dma.last_byte = value;
+            mem.dma.index += 1;
+            mem.dma.active = mem.dma.index < LENGTH;
+        }
+
+        if let Some(page) = mem.dma.requested.take() {
+            // Pages 0xE0~0xFF read from the work RAM mirror
+            let page = if page >= 0xE0 { page - 0x20 } else { page };
+            mem.dma.source = (page as u16) << 8;
+            mem.dma.index = 0;
+            mem.dma.active = true;
         }
-        mem.write(0xff46, 0);
     }
 }
@@ -1,24 +1,224 @@
+use flate2::read::GzDecoder;
 use std::convert::TryInto;
-use std::fs;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use zip::ZipArchive;
 
-use crate::{hardware::Cpu, Config};
+use crate::apu::Apu;
+use crate::cartridge::{has_multicart_logos, Cartridge, CartridgeHeader};
+use crate::dma::Dma;
+use crate::gbs::{self, Gbs};
+use crate::memory::Memory;
+use crate::serial::Serial;
+use crate::timer::Timer;
+use crate::{hardware::Cpu, patch, Config};
 
-pub fn load_rom(config: &Config) -> Vec<[u8; 0x4000]> {
-    let contents = fs::read(&config.rom_path).expect("Something went wrong reading the file");
-    let nb_banks = contents.len() / 0x4000;
-    let mut banks: Vec<[u8; 0x4000]> = Vec::new();
-    for i in 0..nb_banks {
-        let bank = contents[i * 0x4000..(i + 1) * 0x4000].try_into().unwrap();
-        banks.push(bank);
+// Savestates start with these, the first format (CPU and RAM only) had no header
+const SAVESTATE_MAGIC: &[u8] = b"RBSS";
+const SAVESTATE_VERSION: u8 = 2;
+
+pub fn load_rom(config: &Config) -> Result<Vec<[u8; 0x4000]>, String> {
+    let mut contents = read_rom_file(config)
+        .map_err(|e| format!("Unable to read {}: {}", config.rom_path.display(), e))?;
+
+    if let Some(patch_path) = find_patch(config) {
+        contents = patch::apply(&patch_path, &contents)
+            .map_err(|e| format!("Unable to apply patch {}: {}", patch_path.display(), e))?;
+        eprintln!("Applied patch {}", patch_path.display());
+    }
+
+    // GBS music files run from a ROM built around them
+    if gbs::is_gbs(&contents) {
+        return Gbs::parse(&contents).map(|gbs| gbs.build_rom(&contents));
+    }
+
+    if contents.len() < 0x150 {
+        return Err(format!(
+            "ROM is truncated: {} bytes, not even a complete header",
+            contents.len()
+        ));
+    }
+
+    // Pad the last bank if the image doesn't end on a bank boundary
+    let padded_len = contents.len().div_ceil(0x4000) * 0x4000;
+    contents.resize(padded_len, 0xFF);
+
+    let mut banks: Vec<[u8; 0x4000]> = contents
+        .chunks(0x4000)
+        .map(|bank| bank.try_into().unwrap())
+        .collect();
+
+    let header = CartridgeHeader::from_rom(&banks);
+    let declared_banks = match header.rom_size {
+        Some(size) => size / 0x4000,
+        // Unknown size code, trust the file
+        None => return Ok(banks),
+    };
+
+    if banks.len() < declared_banks {
+        // Trimmed ROMs had their trailing 0xFF padding removed: putting it back gives a valid
+        // global checksum. If it still doesn't match, data may be missing, but as the checksum
+        // isn't checked by the hardware the game may run anyway.
+        banks.resize(declared_banks, [0xFF; 0x4000]);
+        if !CartridgeHeader::from_rom(&banks).global_checksum_valid {
+            eprintln!(
+                "Warning: ROM may be truncated: {} bytes, but the header declares {} KiB",
+                padded_len,
+                declared_banks * 16
+            );
+        }
+    } else if banks.len() > declared_banks {
+        let excess = &banks[declared_banks..];
+        let is_padding = excess.iter().flatten().all(|&b| b == 0xFF)
+            || excess.iter().flatten().all(|&b| b == 0x00);
+        let is_mirror = excess
+            .iter()
+            .enumerate()
+            .all(|(i, bank)| bank == &banks[i % declared_banks]);
+        // Multicarts only declare the size of their first game
+        let is_multicart = has_multicart_logos(&banks);
+
+        if is_padding || is_mirror {
+            banks.truncate(declared_banks);
+        } else if !is_multicart {
+            return Err(format!(
+                "ROM is oversized: {} bytes, but the header declares {} KiB",
+                padded_len,
+                declared_banks * 16
+            ));
+        }
+    }
+
+    Ok(banks)
+}
+
+// The patch given on the command line, or one named after the ROM next to it
+fn find_patch(config: &Config) -> Option<PathBuf> {
+    if let Some(patch_path) = config.patch_path {
+        return Some(patch_path.to_path_buf());
+    }
+
+    let rom_name = get_rom_name(config);
+    let folder = config.rom_path.parent().unwrap_or_else(|| Path::new(""));
+    ["ips", "bps", "ups"]
+        .iter()
+        .map(|extension| folder.join(format!("{}.{}", rom_name, extension)))
+        .find(|path| path.is_file())
+}
+
+// None if the ROM isn't a GBS music file
+pub fn load_gbs(config: &Config) -> Result<Option<Gbs>, String> {
+    let contents = read_rom_file(config)
+        .map_err(|e| format!("Unable to read {}: {}", config.rom_path.display(), e))?;
+    if !gbs::is_gbs(&contents) {
+        return Ok(None);
+    }
+    Gbs::parse(&contents).map(Some)
+}
+
+// Compressed ROMs are extracted in memory, anything else is read as is
+fn read_rom_file(config: &Config) -> Result<Vec<u8>, String> {
+    let mut contents = Vec::new();
+    match config.rom_path.extension().and_then(|e| e.to_str()) {
+        Some("zip") => {
+            let mut archive = open_zip(config.rom_path)?;
+            let name = get_zip_entry(&mut archive, config)?;
+            archive
+                .by_name(&name)
+                .map_err(|e| e.to_string())?
+                .read_to_end(&mut contents)
+                .map_err(|e| e.to_string())?;
+        }
+        Some("gz") => {
+            let file = File::open(config.rom_path).map_err(|e| e.to_string())?;
+            GzDecoder::new(file)
+                .read_to_end(&mut contents)
+                .map_err(|e| e.to_string())?;
+        }
+        _ => contents = fs::read(config.rom_path).map_err(|e| e.to_string())?,
+    }
+    Ok(contents)
+}
+
+fn open_zip(path: &Path) -> Result<ZipArchive<File>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    ZipArchive::new(file).map_err(|e| e.to_string())
+}
+
+// The entry named on the command line, or the first Game Boy ROM of the archive
+fn get_zip_entry(archive: &mut ZipArchive<File>, config: &Config) -> Result<String, String> {
+    let names: Vec<String> = (0..archive.len())
+        .filter_map(|i| {
+            archive
+                .by_index(i)
+                .ok()
+                .map(|entry| entry.name().to_string())
+        })
+        .collect();
+
+    match config.zip_entry {
+        Some(entry) => names
+            .into_iter()
+            .find(|name| name == entry)
+            .ok_or_else(|| format!("no entry named {} in the archive", entry)),
+        None => names
+            .into_iter()
+            .find(|name| {
+                let name = name.to_lowercase();
+                name.ends_with(".gb") || name.ends_with(".gbc") || name.ends_with(".gbs")
+            })
+            .ok_or_else(|| "no .gb, .gbc or .gbs file in the archive".to_string()),
     }
+}
 
-    banks
+// DMG, MGB and SGB boot ROMs are 256 bytes, the CGB one is 2304 bytes
+// (0x0000~0x00FF and 0x0200~0x08FF, the cartridge header stays visible in between)
+pub fn load_boot_rom(path: &Path) -> Result<Vec<u8>, String> {
+    let boot_rom =
+        fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    match boot_rom.len() {
+        0x100 | 0x900 => Ok(boot_rom),
+        len => Err(format!(
+            "{} is not a boot ROM ({} bytes, expected 256 or 2304)",
+            path.display(),
+            len
+        )),
+    }
 }
 
-pub fn create_savestate(config: &Config, cpu: &Cpu, ram: &[u8; 0x8000]) {
-    let mut buffer = bincode::serialize(&cpu).unwrap();
-    buffer.append(&mut ram.to_vec());
+// Battery backed memory, None if the game has never been saved
+pub fn load_save(config: &Config) -> Option<Vec<u8>> {
+    match fs::read(get_save_path(config)) {
+        Ok(data) => Some(data),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(_) => panic!("Unable to read save file"),
+    }
+}
+
+pub fn write_save(config: &Config, data: &[u8]) {
+    // Create saves folder if it doesn't exist yet
+    fs::create_dir_all("saves").expect("Unable to create saves folder");
+
+    fs::write(get_save_path(config), data).expect("Unable to write save file");
+}
+
+// The hardware state: CPU, RAM, timer, DMA, serial port, APU and cartridge (RAM, banks and clocks)
+type Savestate = (Cpu, Vec<u8>, Timer, Dma, Serial, Apu, Cartridge);
+
+pub fn create_savestate(config: &Config, cpu: &Cpu, mem: &Memory) {
+    let mut buffer = SAVESTATE_MAGIC.to_vec();
+    buffer.push(SAVESTATE_VERSION);
+    let state = (
+        cpu,
+        &mem.ram[..],
+        &mem.timer,
+        &mem.dma,
+        &mem.serial,
+        &mem.apu,
+        &mem.cartridge,
+    );
+    buffer.append(&mut bincode::serialize(&state).unwrap());
 
     let savestate_path = get_savestate_path(config);
 
@@ -29,7 +229,7 @@ pub fn create_savestate(config: &Config, cpu: &Cpu, ram: &[u8; 0x8000]) {
     fs::write(savestate_path, buffer).expect("Unable to create savestate");
 }
 
-pub fn load_savestate(config: &Config, cpu: &mut Cpu, ram: &mut [u8; 0x8000]) {
+pub fn load_savestate(config: &Config, cpu: &mut Cpu, mem: &mut Memory) {
     let savestate_path = get_savestate_path(config);
 
     // Open and read savestate file. The panics should eventually be replaced with an on-screen message.
@@ -39,22 +239,73 @@ pub fn load_savestate(config: &Config, cpu: &mut Cpu, ram: &mut [u8; 0x8000]) {
         _ => panic!("Unable to read savestate file"),
     });
 
-    let buffer_len = buffer.len();
-    // Restore RAM
-    ram.copy_from_slice(&buffer[buffer_len - 0x8000..]);
+    let header = [SAVESTATE_MAGIC, &[SAVESTATE_VERSION]].concat();
+    if !buffer.starts_with(&header) {
+        eprintln!(
+            "Warning: {} was made by another version of the emulator, it can't be loaded",
+            savestate_path
+        );
+        return;
+    }
+    let (saved_cpu, ram, timer, dma, serial, apu, cartridge): Savestate =
+        bincode::deserialize(&buffer[header.len()..])
+            .expect("Unable to decode savestate, did you edit the savestate file?");
 
-    // Restore CPU
-    *cpu = bincode::deserialize(&buffer[..buffer_len - 0x8000])
-        .expect("Unable to decode CPU data, did you edit the savestate file?");
+    *cpu = saved_cpu;
+    mem.ram.copy_from_slice(&ram);
+    mem.timer = timer;
+    mem.dma = dma;
+    mem.serial.load_state(serial);
+    mem.apu.load_state(apu);
+    mem.cartridge.load_state(cartridge);
 }
 
-fn get_savestate_path(config: &Config) -> String {
-    let rom_name = config
-        .rom_path
+// First unused "recordings/<rom>_<n>.wav", so earlier recordings aren't overwritten
+pub fn get_recording_path(config: &Config) -> PathBuf {
+    // Create recordings folder if it doesn't exist yet
+    fs::create_dir_all("recordings").expect("Unable to create recordings folder");
+
+    let rom_name = get_rom_name(config);
+    (0..)
+        .map(|i| PathBuf::from(format!("recordings/{}_{}.wav", rom_name, i)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+// Name of the ROM itself, so archives share their saves with the uncompressed ROM
+fn get_rom_name(config: &Config) -> String {
+    let file_name = match config.rom_path.extension().and_then(|e| e.to_str()) {
+        Some("zip") => {
+            let mut archive = open_zip(config.rom_path).expect("Unable to open rom archive");
+            get_zip_entry(&mut archive, config).expect("Unable to find rom in archive")
+        }
+        // "game.gb.gz" gives "game.gb"
+        Some("gz") => config
+            .rom_path
+            .file_stem()
+            .expect("Unable to extract rom name")
+            .to_str()
+            .expect("File name doesn't contain valid Unicode")
+            .to_string(),
+        _ => config
+            .rom_path
+            .to_str()
+            .expect("File name doesn't contain valid Unicode")
+            .to_string(),
+    };
+
+    Path::new(&file_name)
         .file_stem()
         .expect("Unable to extract rom name")
         .to_str()
-        .expect("File name doesn't contain valid Unicode");
+        .expect("File name doesn't contain valid Unicode")
+        .to_string()
+}
+
+fn get_savestate_path(config: &Config) -> String {
+    format!("saves/{}_0.savestate", get_rom_name(config))
+}
 
-    format!("saves/{}_0.savestate", rom_name)
+fn get_save_path(config: &Config) -> String {
+    format!("saves/{}.sav", get_rom_name(config))
 }
@@ -0,0 +1,217 @@
+use crate::boot;
+use crate::hardware::Cpu;
+use crate::memory::Memory;
+use std::convert::TryInto;
+use std::fmt;
+
+// Sources:
+// https://ocremix.org/info/GBS_Format_Specification
+// https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware
+//
+// A GBS file is the sound code and data of a game with a header telling how to run it. It is
+// turned into a MBC5 ROM: the data is placed at its load address and the space before it (at
+// least 0x400 bytes) holds the RST and interrupt vectors and a loop waiting for interrupts.
+
+const HEADER_SIZE: usize = 0x70;
+
+// EI, HALT and back to EI, running the play routine on each interrupt
+const IDLE_LOOP: u16 = 0x0060;
+
+const TIMER_ENABLE: u8 = 0x04;
+// Set by files made for the CGB double speed mode
+const DOUBLE_SPEED: u8 = 0x80;
+
+pub struct Gbs {
+    pub songs: u8,
+    pub first_song: u8, // Starting at 1
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl fmt::Display for Gbs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        writeln!(f, "Author: {}", self.author)?;
+        writeln!(f, "Copyright: {}", self.copyright)?;
+        write!(f, "Tracks: {}", self.songs)
+    }
+}
+
+fn read_u16_le(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+// Fixed size fields, padded with zeros
+fn read_string(data: &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+pub fn is_gbs(contents: &[u8]) -> bool {
+    contents.starts_with(b"GBS")
+}
+
+impl Gbs {
+    pub fn parse(contents: &[u8]) -> Result<Gbs, String> {
+        if contents.len() < HEADER_SIZE {
+            return Err("GBS file is truncated".to_string());
+        }
+        if contents[0x03] != 1 {
+            return Err(format!("unsupported GBS version {}", contents[0x03]));
+        }
+        let gbs = Gbs {
+            songs: contents[0x04],
+            first_song: contents[0x05],
+            load_address: read_u16_le(&contents[0x06..]),
+            init_address: read_u16_le(&contents[0x08..]),
+            play_address: read_u16_le(&contents[0x0A..]),
+            stack_pointer: read_u16_le(&contents[0x0C..]),
+            timer_modulo: contents[0x0E],
+            timer_control: contents[0x0F],
+            title: read_string(&contents[0x10..0x30]),
+            author: read_string(&contents[0x30..0x50]),
+            copyright: read_string(&contents[0x50..0x70]),
+        };
+        if gbs.songs == 0 {
+            return Err("GBS file has no tracks".to_string());
+        }
+        if gbs.load_address < 0x0400 || gbs.load_address >= 0x8000 {
+            return Err(format!(
+                "GBS load address {:#06x} is outside of 0x0400~0x7FFF",
+                gbs.load_address
+            ));
+        }
+        Ok(gbs)
+    }
+
+    // ROM running the file, with the data at the load address and its banks after it
+    pub fn build_rom(&self, contents: &[u8]) -> Vec<[u8; 0x4000]> {
+        let data = &contents[HEADER_SIZE..];
+        let load = self.load_address as usize;
+        let banks = (load + data.len())
+            .div_ceil(0x4000)
+            .next_power_of_two()
+            .max(2);
+        let mut rom = vec![0; banks * 0x4000];
+        rom[load..load + data.len()].copy_from_slice(data);
+
+        // RST instructions jump to the same offset from the load address
+        for rst in (0x00..0x40).step_by(8) {
+            let target = (load + rst) as u16;
+            rom[rst..rst + 3].copy_from_slice(&[0xC3, target as u8, (target >> 8) as u8]);
+        }
+        // VBlank and timer interrupts: CALL play, RETI
+        let play = self.play_address;
+        for &vector in &[0x40, 0x50] {
+            rom[vector..vector + 4].copy_from_slice(&[0xCD, play as u8, (play >> 8) as u8, 0xD9]);
+        }
+        let idle = IDLE_LOOP as usize;
+        rom[idle..idle + 4].copy_from_slice(&[0xFB, 0x76, 0x18, 0xFC]);
+
+        // MBC5 with 8 KiB of RAM for 0xA000~0xBFFF, which some drivers use
+        rom[0x0147] = 0x1A;
+        rom[0x0148] = banks.trailing_zeros() as u8 - 1;
+        rom[0x0149] = 0x02;
+
+        rom.chunks(0x4000)
+            .map(|bank| bank.try_into().unwrap())
+            .collect()
+    }
+
+    // Track to start with, from 0
+    pub fn first_track(&self) -> u8 {
+        self.first_song.saturating_sub(1) % self.songs
+    }
+
+    // Interrupts enabled for the play routine
+    fn play_interrupt(&self) -> u8 {
+        if self.timer_control & TIMER_ENABLE > 0 {
+            0b100
+        } else {
+            0b1
+        }
+    }
+
+    // Resets the machine and calls init for a track, starting at 0. It returns to the idle loop,
+    // where the play routine is called at the rate set by the header.
+    pub fn start_track(&self, track: u8, model: boot::Model, cpu: &mut Cpu, mem: &mut Memory) {
+        // The boot ROM would hide the vectors
+        mem.write(0xFF50, 0x01);
+
+        // Work RAM and HRAM are cleared, the APU is turned off to silence the previous track
+        for byte in mem.ram[0x4000..0x6000].iter_mut() {
+            *byte = 0;
+        }
+        for byte in mem.ram[0x7F80..].iter_mut() {
+            *byte = 0;
+        }
+        mem.write(0xFF26, 0x00);
+        boot::init_io(model, mem);
+        mem.write(0x0000, 0x0A); // Cartridge RAM enable
+        mem.write(0x2000, 0x01);
+        for addr in 0xA000..=0xBFFF {
+            mem.write(addr, 0);
+        }
+
+        // Double speed (bit 7 of TAC) needs a CGB, other models play these at half the tempo
+        if self.timer_control & DOUBLE_SPEED > 0 {
+            if model.is_cgb() {
+                mem.ram[0x7F4D] |= 0x80;
+            } else {
+                eprintln!(
+                    "Warning: this file uses the CGB double speed mode, it plays at half the tempo on {}",
+                    model
+                );
+            }
+        }
+        mem.write(0xFF06, self.timer_modulo);
+        mem.write(0xFF07, self.timer_control & 0x07);
+        mem.write(0xFF0F, 0x00);
+        mem.write(0xFFFF, self.play_interrupt());
+
+        boot::init_registers(model, cpu, 0);
+        cpu.mie = false;
+        cpu.pending_mie = None;
+        cpu.is_halted = false;
+        cpu.halt_bug = false;
+        cpu.is_stopped = false;
+        cpu.is_locked = false;
+        cpu.a = track;
+        cpu.sp = self.stack_pointer;
+        cpu.write_u16_to_stack(IDLE_LOOP, mem);
+        cpu.pc = self.init_address;
+    }
+}
+
+// Current track of a GBS file, changed with hotkeys
+pub struct Player {
+    pub gbs: Gbs,
+    pub track: u8, // Starting at 0
+}
+
+impl Player {
+    pub fn new(gbs: Gbs) -> Player {
+        let track = gbs.first_track();
+        Player { gbs, track }
+    }
+
+    pub fn play(&self, model: boot::Model, cpu: &mut Cpu, mem: &mut Memory) {
+        println!("Track {}/{}", self.track + 1, self.gbs.songs);
+        self.gbs.start_track(self.track, model, cpu, mem);
+    }
+
+    pub fn next(&mut self) {
+        self.track = (self.track + 1) % self.gbs.songs;
+    }
+
+    pub fn previous(&mut self) {
+        self.track = self.track.checked_sub(1).unwrap_or(self.gbs.songs - 1);
+    }
+}
@@ -1,12 +1,27 @@
-use sdl2::event::Event;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::GameController;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::rect::Rect;
 use sdl2::render::{Texture, WindowCanvas};
-use sdl2::EventPump;
+use sdl2::{EventPump, VideoSubsystem};
 
+use crate::apu::{self, Apu};
+use crate::scope::Scope;
 use crate::Config;
 
+// Samples queued beyond this are dropped to keep the latency down (100ms)
+const MAX_QUEUED_SAMPLES: u32 = apu::SAMPLE_RATE / 10;
+
 pub struct Gui {
     pub canvas: WindowCanvas,
     pub events: EventPump,
+    pub controller: Option<GameController>,
+    pub rumble: bool,
+    pub audio: Option<AudioQueue<f32>>,
+    pub pressed_keys: Vec<Keycode>, // Keys pressed since the last update, for toggles
+    pub scope: Option<Scope>,
+    video: VideoSubsystem,
 }
 
 impl Gui {
@@ -26,12 +41,12 @@ impl Gui {
             .build()
             .expect("could not initialize video subsystem");
 
-        let mut canvas = window
-            .into_canvas()
-            .accelerated()
-            .present_vsync()
-            .build()
-            .expect("could not make a canvas");
+        // Without video sync, presenting must not block so --framerate 0 is really unlimited
+        let mut canvas = window.into_canvas().accelerated();
+        if config.video_sync {
+            canvas = canvas.present_vsync();
+        }
+        let mut canvas = canvas.build().expect("could not make a canvas");
 
         canvas
             .set_scale(3.0, 3.0)
@@ -39,12 +54,71 @@ impl Gui {
 
         canvas.clear();
 
+        // Use the first gamepad found, if any. Without the subsystem, the keyboard is still there.
+        let controller = sdl_context
+            .game_controller()
+            .ok()
+            .and_then(|controller_subsystem| {
+                (0..controller_subsystem.num_joysticks().unwrap_or(0))
+                    .filter(|&i| controller_subsystem.is_game_controller(i))
+                    .find_map(|i| controller_subsystem.open(i).ok())
+            });
+        if let Some(controller) = &controller {
+            println!("Using controller: {}", controller.name());
+        }
+
+        // Run without sound rather than not at all
+        let audio = sdl_context
+            .audio()
+            .and_then(|audio_subsystem| {
+                let spec = AudioSpecDesired {
+                    freq: Some(apu::SAMPLE_RATE as i32),
+                    channels: Some(2),
+                    samples: Some(1024),
+                };
+                audio_subsystem.open_queue::<f32, _>(None, &spec)
+            })
+            .map_err(|e| eprintln!("Warning: no audio output ({})", e))
+            .ok();
+        if let Some(audio) = &audio {
+            audio.resume();
+        }
+
         let event_pump = sdl_context.event_pump().unwrap();
-        Gui {
+        let mut gui = Gui {
             //context: sdl_context,
             //video: video_subsystem,
             canvas: canvas,
             events: event_pump,
+            controller,
+            rumble: false,
+            audio,
+            pressed_keys: Vec::new(),
+            scope: None,
+            video: video_subsystem,
+        };
+        if config.scope {
+            gui.toggle_scope();
+        }
+        gui
+    }
+
+    // Opens or closes the channel viewer
+    pub fn toggle_scope(&mut self) {
+        if self.scope.take().is_some() {
+            return;
+        }
+        let window = self.canvas.window();
+        let (x, y) = window.position();
+        let next_to = (x, y, window.size().0);
+        self.scope = Scope::new(&self.video, next_to)
+            .map_err(|e| eprintln!("Warning: unable to open the channel viewer ({})", e))
+            .ok();
+    }
+
+    pub fn draw_scope(&mut self, apu: &Apu) {
+        if let Some(scope) = &mut self.scope {
+            scope.draw(apu).expect("Couldn't draw channel viewer");
         }
     }
 
@@ -54,11 +128,33 @@ impl Gui {
     }
 
     pub fn update(&mut self) -> bool {
-        if self
-            .events
-            .poll_iter()
-            .any(|i| matches!(i, Event::Quit {..}))
-        {
+        self.pressed_keys.clear();
+        let mut quit = false;
+        let scope_id = self.scope.as_ref().map(|scope| scope.window_id());
+        for event in self.events.poll_iter() {
+            match event {
+                Event::Quit { .. } => quit = true,
+                // With the channel viewer open, closing a window doesn't quit by itself
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } => {
+                    if Some(window_id) == scope_id {
+                        self.scope = None;
+                    } else {
+                        quit = true;
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    repeat: false,
+                    ..
+                } => self.pressed_keys.push(keycode),
+                _ => {}
+            }
+        }
+        if quit {
             false
         } else {
             self.canvas.present();
@@ -112,4 +208,41 @@ impl Gui {
             .copy(&texture, None, None)
             .expect("Couldn't copy texture on canvas");
     }
+
+    // Interleaved stereo samples from the APU
+    pub fn queue_audio(&mut self, samples: &[f32]) {
+        if let Some(audio) = &self.audio {
+            if self.queued_audio() < MAX_QUEUED_SAMPLES {
+                audio.queue(samples);
+            }
+        }
+    }
+
+    // Samples per channel waiting to be played
+    pub fn queued_audio(&self) -> u32 {
+        match &self.audio {
+            Some(audio) => audio.size() / (2 * std::mem::size_of::<f32>() as u32),
+            None => 0,
+        }
+    }
+
+    pub fn set_rumble(&mut self, on: bool) {
+        self.rumble = on;
+        if let Some(controller) = &mut self.controller {
+            // The motor is either fully on or off, keep it going until the game stops it
+            let strength = if on { 0xFFFF } else { 0 };
+            // Not all controllers can rumble, the on-screen indicator is enough for those
+            let _ = controller.set_rumble(strength, strength, 60_000);
+        }
+    }
+
+    // Small on-screen indicator lit while the cartridge rumble motor is running
+    pub fn draw_rumble(&mut self) {
+        if self.rumble {
+            self.canvas.set_draw_color((200, 40, 40));
+            self.canvas
+                .fill_rect(Rect::new(153, 2, 5, 5))
+                .expect("Couldn't draw rumble indicator");
+        }
+    }
 }
@@ -99,6 +99,9 @@ pub struct Cpu {
     pub pending_mie: Option<bool>,
     pub pending_ticks: u8,
     pub is_halted: bool,
+    pub halt_bug: bool,
+    pub is_stopped: bool,
+    pub is_locked: bool,
 }
 
 impl Cpu {
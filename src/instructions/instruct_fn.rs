@@ -216,7 +216,59 @@ pub fn set_carry(cpu: &mut Cpu, _mem: &mut Memory, flip: bool) {
 }
 
 pub fn change_mie(cpu: &mut Cpu, _mem: &mut Memory, enable: bool) {
-    cpu.pending_mie = Some(enable);
+    if enable {
+        // EI only takes effect after the next instruction
+        cpu.pending_mie = Some(true);
+    } else {
+        // DI is immediate, and cancels an EI that hasn't taken effect yet
+        cpu.mie = false;
+        cpu.pending_mie = None;
+    }
+}
+
+// https://gbdev.io/pandocs/halt.html
+pub fn halt(cpu: &mut Cpu, mem: &mut Memory) {
+    let interrupt_pending = mem.read(0xFFFF) & mem.read(0xFF0F) & 0x1F > 0;
+
+    if !cpu.mie && interrupt_pending {
+        // HALT bug: the CPU doesn't halt, and fails to increment PC after the next opcode
+        cpu.halt_bug = true;
+    } else {
+        // Without IME, the CPU wakes up on the next interrupt without servicing it
+        cpu.is_halted = true;
+    }
+}
+
+// The CPU hangs until the console is turned off, PC stays on the illegal opcode
+pub fn illegal(cpu: &mut Cpu, _mem: &mut Memory) {
+    cpu.pc = cpu.pc.wrapping_sub(1);
+    cpu.is_locked = true;
+}
+
+// https://gbdev.io/pandocs/Reducing_Power_Consumption.html#using-the-stop-instruction
+pub fn stop(cpu: &mut Cpu, mem: &mut Memory) {
+    let button_held = mem.read(0xFF00) & 0x0F != 0x0F;
+    let interrupt_pending = mem.read(0xFFFF) & mem.read(0xFF0F) & 0x1F > 0;
+
+    if button_held {
+        // STOP never starts: with an interrupt pending it's a 1 byte NOP, otherwise a HALT
+        if !interrupt_pending {
+            cpu.pc = cpu.pc.wrapping_add(1);
+            cpu.is_halted = true;
+        }
+        return;
+    }
+
+    // The byte after STOP is skipped
+    cpu.pc = cpu.pc.wrapping_add(1);
+    mem.write(0xFF04, 0);
+
+    // An armed KEY1 makes STOP switch the CGB speed instead
+    if mem.model.is_cgb() && mem.read(0xFF4D) & 0x01 > 0 {
+        mem.ram[0x7F4D] = (mem.ram[0x7F4D] ^ 0x80) & 0x80;
+        return;
+    }
+    cpu.is_stopped = true;
 }
 
 pub fn rotate(
@@ -372,8 +424,10 @@ pub fn ret(cpu: &mut Cpu, mem: &mut Memory, flag: Option<&Flag>, is_set: bool, i
         let addr = cpu.read_u16_from_stack(mem);
         cpu.pc = addr;
 
+        // RETI enables interrupts right away, unlike EI
         if i_enable {
             cpu.mie = true;
+            cpu.pending_mie = None;
         }
     }
 }
@@ -34,6 +34,8 @@ pub enum InstructType {
     SetCarry(bool),               // Flip flag (true) / set flag (false)
     Nop,
     Halt,
+    Stop,
+    Illegal,
     ChangeMie(bool),                             // Enable interrupts (true) / disable interrupts (false)
     Rotate(RegU8, bool, bool, bool, bool, bool), // Register, left/right, through carry, update_z, shift/rotate, keep_msb
     Swap(RegU8),                                 // Register
@@ -71,7 +73,9 @@ impl InstructType {
             Cpl => instruct_fn::cpl(cpu, mem),
             SetCarry(flip) => instruct_fn::set_carry(cpu, mem, *flip),
             Nop => {}
-            Halt => cpu.is_halted = true,
+            Halt => instruct_fn::halt(cpu, mem),
+            Stop => instruct_fn::stop(cpu, mem),
+            Illegal => instruct_fn::illegal(cpu, mem),
             ChangeMie(enable) => instruct_fn::change_mie(cpu, mem, *enable),
             Rotate(reg, left, through_carry, update_z, shift, keep_msb) => {
                 instruct_fn::rotate(cpu, mem, reg, *left, *through_carry, *update_z, *shift, *keep_msb)
@@ -116,6 +120,8 @@ impl fmt::Display for InstructType {
             SetCarry(flip) => write!(f, "{}", if *flip { "CCF" } else { "SCF" }),
             Nop => write!(f, "NOP"),
             Halt => write!(f, "HALT"),
+            Stop => write!(f, "STOP"),
+            Illegal => write!(f, "ILLEGAL"),
             ChangeMie(enable) => write!(f, "{}", if *enable { "EI" } else { "DI" }),
             Rotate(reg, left, through_carry, update_z, shift, keep_msb) => write!(f, "{}{}{}{}{}",
                 if *shift { "S" } else { "R" },
@@ -324,10 +330,8 @@ impl Instruct {
             0x3F => inst!(SetCarry(true), 4, "Flip C flag, reset N and H"),
             0x37 => inst!(SetCarry(false), 4, "Set C flag, reset N and H"),
             0x00 => inst!(Nop, 4, "Aussi inutile que les cours de GE00"),
-            #[allow(unreachable_code)]
             0x76 => inst!(Halt, 4, "Stop CPU until interrupt is received"),
-            #[allow(unreachable_code)]
-            0x10 => inst!(todo!(), 4, "/!\\ Stop CPU and LCD until button pressed"),
+            0x10 => inst!(Stop, 4, "Stop CPU and LCD until button pressed"),
             0xF3 => inst!(ChangeMie(false), 4, "Disable interrupts"),
             0xFB => inst!(ChangeMie(true), 4, "Enable interrupts"),
             //Rotates
@@ -412,7 +416,7 @@ impl Instruct {
             0xD9 => inst!(Ret(None, false, true), 16, "Return from subroutine and enable interrupts"),
             //Undefined
             0xD3 | 0xE3 | 0xE4 | 0xF4 | 0xDB | 0xEB | 0xEC | 0xFC | 0xDD | 0xED | 0xFD => {
-                inst!(Nop, 4, "Undefined NOP")
+                inst!(Illegal, 4, "Illegal opcode, locks up the CPU")
             }
         }
     }
@@ -1,86 +1,82 @@
 use crate::hardware::Cpu;
 use crate::memory::Memory;
 
-const INTERRUPTS_VBLANK: u8 = 1 << 0;
-const INTERRUPTS_LCDSTAT: u8 = 1 << 1;
-const INTERRUPTS_TIMER: u8 = 1 << 2;
-const INTERRUPTS_SERIAL: u8 = 1 << 3;
-const INTERRUPTS_JOYPAD: u8 = 1 << 4;
+// Sources:
+// https://gbdev.io/pandocs/Interrupts.html
+// https://github.com/Gekkio/mooneye-test-suite (intr_timing, ie_push, ei_sequence, rapid_di_ei)
 
+const INTERRUPTS_ALL: u8 = 0b0001_1111;
 
+// Dispatch: 2 wait cycles, 2 cycles to push PC and 1 to jump
+const DISPATCH_TICKS: u8 = 20;
 
+// Returns true if an interrupt is pending, which wakes the CPU from HALT even with IME off
 pub fn interrupt_check(cpu: &mut Cpu, mem: &mut Memory) -> bool {
-    if mem.read(0xFFFF) & mem.read(0xFF0F) > 0 {
-        if cpu.mie {
-            let mask: u8 = mem.read(0xFFFF) & mem.read(0xFF0F);
-
-            if mask & INTERRUPTS_VBLANK > 0 {
-                mem.write(0xFF0F, mem.read(0xFF0F) & !INTERRUPTS_VBLANK);
-                vblank(cpu, mem);
-                //println!("vblank");
-                return true;
-            }
-
-            if mask & INTERRUPTS_LCDSTAT > 0 {
-                mem.write(0xFF0F, mem.read(0xFF0F) & !INTERRUPTS_LCDSTAT);
-                lcd_stat(cpu, mem);
-                //println!("stat");
-                return true;
-            }
+    // The upper bits of IF always read as 1 and are not interrupts
+    if mem.read(0xFFFF) & mem.read(0xFF0F) & INTERRUPTS_ALL == 0 {
+        return false;
+    }
+    if cpu.mie {
+        dispatch(cpu, mem);
+    }
+    true
+}
 
-            if mask & INTERRUPTS_TIMER > 0 {
-                mem.write(0xFF0F, mem.read(0xFF0F) & !INTERRUPTS_TIMER);
-                timer(cpu, mem);
-                //println!("timer");
-                return true;
-            }
+fn dispatch(cpu: &mut Cpu, mem: &mut Memory) {
+    cpu.mie = false;
+    cpu.pending_mie = None;
+    cpu.add_ticks(DISPATCH_TICKS);
 
-            if mask & INTERRUPTS_SERIAL > 0 {
-                mem.write(0xFF0F, mem.read(0xFF0F) & !INTERRUPTS_SERIAL);
-                serial(cpu, mem);
-                //println!("serial");
-                return true;
-            }
+    // The interrupt is only picked after the upper byte of PC is pushed: if that push
+    // overwrote IE (SP = 0x0000) and disabled it, the CPU jumps to 0x0000 instead
+    cpu.sp = cpu.sp.wrapping_sub(1);
+    mem.write(cpu.sp, (cpu.pc >> 8) as u8);
+    let pending = mem.read(0xFFFF) & mem.read(0xFF0F) & INTERRUPTS_ALL;
+    cpu.sp = cpu.sp.wrapping_sub(1);
+    mem.write(cpu.sp, cpu.pc as u8);
 
-            if mask & INTERRUPTS_JOYPAD > 0 {
-                mem.write(0xFF0F, mem.read(0xFF0F) & !INTERRUPTS_JOYPAD);
-                joypad(cpu, mem);
-                //println!("joypad");
-                return true;
-            }
-        }
-        true
-    } else {
-        false
+    if pending == 0 {
+        cpu.pc = 0x0000;
+        return;
     }
-}
 
-pub fn vblank(cpu: &mut Cpu, mem: &mut Memory) {
-    cpu.mie = false;
-    cpu.write_u16_to_stack(cpu.pc, mem);
-    cpu.pc = 0x40; // +12 ticks
+    // Lowest bit first: VBlank, LCD STAT, timer, serial, joypad
+    let bit = pending.trailing_zeros() as u16;
+    mem.write(0xFF0F, mem.read(0xFF0F) & !(1 << bit));
+    cpu.pc = 0x40 + bit * 8;
 }
 
-pub fn lcd_stat(cpu: &mut Cpu, mem: &mut Memory) {
-    cpu.mie = false;
-    cpu.write_u16_to_stack(cpu.pc, mem);
-    cpu.pc = 0x48; // +12 ticks
-}
+#[cfg(test)]
+mod tests {
+    use super::{interrupt_check, INTERRUPTS_ALL};
+    use crate::testing::power_on_program;
 
-pub fn timer(cpu: &mut Cpu, mem: &mut Memory) {
-    cpu.mie = false;
-    cpu.write_u16_to_stack(cpu.pc, mem);
-    cpu.pc = 0x50; // +12 ticks
-}
+    #[test]
+    fn dispatch_jumps_to_vector() {
+        let (mut cpu, _, _, mut mem) = power_on_program("dispatch", &[0x00]);
+        cpu.mie = true;
+        cpu.sp = 0xFFFE;
+        mem.write(0xFFFF, 0x04);
+        mem.write(0xFF0F, 0x04);
 
-pub fn serial(cpu: &mut Cpu, mem: &mut Memory) {
-    cpu.mie = false;
-    cpu.write_u16_to_stack(cpu.pc, mem);
-    cpu.pc = 0x58; // +12 ticks
-}
+        assert!(interrupt_check(&mut cpu, &mut mem));
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(mem.read(0xFF0F) & INTERRUPTS_ALL, 0x00);
+    }
 
-pub fn joypad(cpu: &mut Cpu, mem: &mut Memory) {
-    cpu.mie = false;
-    cpu.write_u16_to_stack(cpu.pc, mem);
-    cpu.pc = 0x60; // +12 ticks
+    #[test]
+    fn ie_push_cancels_dispatch() {
+        // With SP = 0x0000, the upper byte of PC (0x01) is pushed onto IE and disables the timer
+        let (mut cpu, _, _, mut mem) = power_on_program("ie_push", &[0x00]);
+        cpu.mie = true;
+        cpu.sp = 0x0000;
+        mem.write(0xFFFF, 0x04);
+        mem.write(0xFF0F, 0x04);
+
+        assert!(interrupt_check(&mut cpu, &mut mem));
+        assert_eq!(mem.read(0xFFFF), 0x01);
+        assert_eq!(cpu.pc, 0x0000);
+        // The interrupt wasn't acknowledged
+        assert_eq!(mem.read(0xFF0F) & INTERRUPTS_ALL, 0x04);
+    }
 }
@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+// Link cable between two emulators over TCP.
+//
+// Both sides count the scanlines they ran and tell the other one after each line. A side that
+// gets more than SYNC_LINES ahead waits for its partner, so the two Game Boys run in lockstep
+// even though each one paces itself. The side clocking a transfer sends its byte when it starts
+// and gets the byte of the other side back before the 8 bits are done.
+
+// Lines a side may run ahead of its partner
+const SYNC_LINES: u64 = 4;
+
+// Silence after which the partner is considered gone, rather than waiting for it forever
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Messages: a kind byte followed by a little endian u64
+const SYNC: u8 = 0; // Lines ran so far
+const TRANSFER: u8 = 1; // Byte shifted out by the side clocking the transfer
+const REPLY: u8 = 2; // Byte shifted out by the other side in return
+
+pub struct Link {
+    stream: TcpStream,
+    lines: u64,
+    partner_lines: u64,
+    transfers: VecDeque<u8>, // Received, waiting to be handled by the serial port
+    reply: Option<u8>,
+}
+
+impl Link {
+    // Waits for the other emulator to connect
+    pub fn host(addr: &str) -> Result<Link, String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("Unable to listen on {}: {}", addr, e))?;
+        println!("Waiting for the other Game Boy on {}", addr);
+        let (stream, partner) = listener.accept().map_err(|e| e.to_string())?;
+        println!("Link cable connected to {}", partner);
+        Link::new(stream)
+    }
+
+    pub fn connect(addr: &str) -> Result<Link, String> {
+        let stream = TcpStream::connect(addr)
+            .map_err(|e| format!("Unable to connect to {}: {}", addr, e))?;
+        println!("Link cable connected to {}", addr);
+        Link::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> Result<Link, String> {
+        // Messages are tiny and waited for, don't let them sit in a buffer
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        Ok(Link {
+            stream,
+            lines: 0,
+            partner_lines: 0,
+            transfers: VecDeque::new(),
+            reply: None,
+        })
+    }
+
+    fn send(&mut self, kind: u8, value: u64) -> io::Result<()> {
+        let mut message = [kind; 9];
+        message[1..].copy_from_slice(&value.to_le_bytes());
+        self.stream.write_all(&message)
+    }
+
+    // Blocks until the next message, failing if the partner is gone or silent for too long
+    pub fn wait(&mut self) -> io::Result<()> {
+        let mut message = [0; 9];
+        self.stream
+            .read_exact(&mut message)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    io::Error::new(io::ErrorKind::TimedOut, "no answer from the other Game Boy")
+                }
+                _ => e,
+            })?;
+        let mut value = [0; 8];
+        value.copy_from_slice(&message[1..]);
+        let value = u64::from_le_bytes(value);
+        match message[0] {
+            SYNC => self.partner_lines = value,
+            TRANSFER => self.transfers.push_back(value as u8),
+            REPLY => self.reply = Some(value as u8),
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message {}", kind),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn start_transfer(&mut self, data: u8) -> io::Result<()> {
+        self.reply = None;
+        self.send(TRANSFER, data as u64)
+    }
+
+    pub fn reply(&mut self, data: u8) -> io::Result<()> {
+        self.send(REPLY, data as u64)
+    }
+
+    pub fn next_transfer(&mut self) -> Option<u8> {
+        self.transfers.pop_front()
+    }
+
+    pub fn take_reply(&mut self) -> Option<u8> {
+        self.reply.take()
+    }
+
+    pub fn add_lines(&mut self, lines: u64) -> io::Result<()> {
+        self.lines += lines;
+        self.send(SYNC, self.lines)
+    }
+
+    pub fn ahead(&self) -> bool {
+        self.lines > self.partner_lines + SYNC_LINES
+    }
+}
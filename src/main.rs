@@ -1,19 +1,35 @@
+mod apu;
+mod boot;
+mod cartridge;
 mod controls;
 mod dma;
 mod file_io;
+mod gbs;
 mod gui;
 mod hardware;
 mod instructions;
 mod interrupts;
+mod link;
 mod master;
 mod memory;
+mod pacing;
+mod patch;
+mod scope;
+mod serde_arrays;
+mod serial;
+#[cfg(test)]
+mod testing;
 mod timer;
 const PX_TRANSFER: u8 = 2;
 
-use sdl2::gfx::framerate::FPSManager;
+use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
 use std::cmp;
+use std::collections::VecDeque;
 use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
 #[macro_use]
 extern crate clap;
@@ -22,7 +38,20 @@ pub struct Config<'a> {
     pub rom_path: &'a Path,
     pub debug: u32,
     pub full_screen: bool,
-    pub framerate: u32,
+    pub framerate: f64,
+    pub video_sync: bool,
+    pub camera_source: Option<&'a Path>,
+    pub zip_entry: Option<&'a str>,
+    pub patch_path: Option<&'a Path>,
+    pub boot_rom_path: Option<&'a Path>,
+    pub model: boot::Model,
+    pub break_on_illegal: bool,
+    pub record_audio: Option<&'a Path>,
+    pub record_channels: bool,
+    pub scope: bool,
+    pub print_serial: bool,
+    pub link_host: Option<&'a str>,
+    pub link_connect: Option<&'a str>,
 }
 
 fn main() {
@@ -30,13 +59,52 @@ fn main() {
         (version: crate_version!())
         (author: crate_authors!(", "))
         (about: crate_description!())
-        (@arg ROM: +required "Sets the ROM to use")
+        (@setting SubcommandsNegateReqs)
+        (@arg ROM: +required "Sets the ROM to use (.gb, .gbc, .gbs music file, or compressed in a .zip or .gz)")
+        (@arg zip_entry: --("zip-entry") +takes_value "ROM to use inside a .zip archive. Default is the first .gb or .gbc file")
+        (@arg patch: -p --patch +takes_value "IPS, BPS or UPS patch to apply. Default is a patch named after the ROM next to it")
         (@arg debug: -d ... "Sets the level of debugging information")
+        (@arg break_on_illegal: --("break-on-illegal") "Switches to step by step with a full debug dump when the CPU locks up on an illegal opcode")
         (@arg fullscreen: -F --fullscreen "Runs the emulator in full screen mode")
-        (@arg framerate: -f --framerate +takes_value "Sets FPS. Default is 60, use 0 for unlimited. Note: this changes the game speed as well")
+        (@arg framerate: -f --framerate +takes_value "Sets FPS. Default is the Game Boy's 59.73, use 0 for unlimited. Note: this changes the game speed as well")
+        (@arg video_sync: --("video-sync") "Paces the emulator with the display instead of the audio output")
+        (@arg bootrom: --bootrom +takes_value "Boot ROM to run before the game")
+        (@arg model: -m --model +takes_value "Game Boy model: dmg0, dmg (default), mgb, sgb, sgb2 or cgb. Sets the state the game starts in without a boot ROM")
+        (@arg record_audio: --("record-audio") +takes_value "Records the sound to a WAV file from the start. F4 starts and stops recordings in the recordings folder")
+        (@arg record_channels: --("record-channels") "Also records each sound channel to its own WAV file, next to the mix")
+        (@arg scope: --scope "Opens the sound channel viewer (F9). Keys 1~4 mute the channels, F5~F8 solo them")
+        (@arg print_serial: --("print-serial") "Prints the bytes sent over the link cable, where test ROMs write their results")
+        (@arg link_host: --("link-host") +takes_value conflicts_with[link_connect] "Waits for another emulator to plug a link cable in, on an address like 127.0.0.1:8765")
+        (@arg link_connect: --("link-connect") +takes_value "Plugs a link cable into an emulator started with --link-host at this address")
+        (@arg camera: --camera +takes_value "Image seen by the Game Boy Camera: a PNG file, a folder of frames or \"pattern\" (default)")
+        (@subcommand info =>
+            (about: "Prints the cartridge header of a ROM")
+            (@arg ROM: +required "ROM to inspect")
+            (@arg zip_entry: --("zip-entry") +takes_value "ROM to inspect inside a .zip archive")
+            (@arg patch: -p --patch +takes_value "IPS, BPS or UPS patch to apply before inspecting")
+            (@arg json: --json "Prints the header as JSON")
+        )
+        (@subcommand render =>
+            (about: "Plays a track of a GBS music file into a WAV file, without opening a window")
+            (@arg GBS: +required "GBS file to play")
+            (@arg OUTPUT: +required "WAV file to write")
+            (@arg track: -t --track +takes_value "Track to play, starting at 1. Default is the first track set by the file")
+            (@arg seconds: -s --seconds +takes_value "Length of the recording. Default is 120")
+            (@arg record_channels: --("record-channels") "Also records each sound channel to its own WAV file")
+            (@arg model: -m --model +takes_value "Game Boy model: dmg0, dmg (default), mgb, sgb, sgb2 or cgb")
+        )
     )
     .get_matches();
 
+    if let Some(info_matches) = matches.subcommand_matches("info") {
+        info(info_matches);
+        return;
+    }
+    if let Some(render_matches) = matches.subcommand_matches("render") {
+        render(render_matches);
+        return;
+    }
+
     // Calling .unwrap() is safe here because "INPUT" is required
     let rom_path = Path::new(matches.value_of("ROM").unwrap());
 
@@ -44,14 +112,15 @@ fn main() {
     // (i.e. 'rust_boy -d -d -d' or 'rust_boy -ddd' vs 'rust_boy -d'
     let debug = cmp::min(matches.occurrences_of("debug") as u32, 2);
 
-    let framerate = value_t!(matches, "framerate", u32).unwrap_or_else(|e| match e.kind {
-        clap::ErrorKind::ArgumentNotFound => 60,
+    let framerate = value_t!(matches, "framerate", f64).unwrap_or_else(|e| match e.kind {
+        clap::ErrorKind::ArgumentNotFound => pacing::FRAME_RATE,
         clap::ErrorKind::ValueValidation => {
             println!(
-                "Warning: \"{}\" is not a valid FPS value, defaulted to 60.",
-                matches.value_of("framerate").unwrap()
+                "Warning: \"{}\" is not a valid FPS value, defaulted to {:.2}.",
+                matches.value_of("framerate").unwrap(),
+                pacing::FRAME_RATE
             );
-            60
+            pacing::FRAME_RATE
         }
         _ => e.exit(),
     });
@@ -61,6 +130,19 @@ fn main() {
         debug,
         full_screen: matches.is_present("fullscreen"),
         framerate,
+        video_sync: matches.is_present("video_sync"),
+        camera_source: matches.value_of("camera").map(Path::new),
+        zip_entry: matches.value_of("zip_entry"),
+        patch_path: matches.value_of("patch").map(Path::new),
+        boot_rom_path: matches.value_of("bootrom").map(Path::new),
+        model: get_model(&matches),
+        break_on_illegal: matches.is_present("break_on_illegal"),
+        record_audio: matches.value_of("record_audio").map(Path::new),
+        record_channels: matches.is_present("record_channels"),
+        scope: matches.is_present("scope"),
+        print_serial: matches.is_present("print_serial"),
+        link_host: matches.value_of("link_host"),
+        link_connect: matches.value_of("link_connect"),
     };
 
     if config.debug >= 1 {
@@ -68,13 +150,130 @@ fn main() {
     }
 
     // Initialize both cartridge and RAM
-    let mut mem = memory::Memory::new(&config);
+    let mut mem = memory::Memory::new(&config).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
     if config.debug >= 1 {
+        println!("{}", mem.cartridge.header);
         println!("Cartridge kind: {}", mem.cartridge.kind);
         println!("Number of banks: {}", mem.cartridge.banks.len());
+        println!("Model: {}", config.model);
+    }
+
+    let link = match (config.link_host, config.link_connect) {
+        (Some(addr), _) => Some(link::Link::host(addr)),
+        (None, Some(addr)) => Some(link::Link::connect(addr)),
+        (None, None) => None,
+    };
+    if let Some(link) = link {
+        mem.serial.link = Some(link.unwrap_or_else(|e| {
+            println!("Error: {}", e);
+            process::exit(1);
+        }));
+    }
+
+    let mut window: gui::Gui = gui::Gui::new(&config);
+    let creator = window.canvas.texture_creator();
+    let mut texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
+        .expect("Couldn't create texture");
+
+    let gbs = file_io::load_gbs(&config).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+    let (mut cpu, mut gpu, mut controls, mut master) = power_on(&config, &mut mem);
+    let mut player = gbs.map(gbs::Player::new);
+    if let Some(player) = &player {
+        println!("{}", player.gbs);
+        player.play(config.model, &mut cpu, &mut mem);
+    }
+    //ram[0xff05] = 255;
+    //ram[0xffff] = 0;
+    //ram[0xff0f] = 0;
+    //ram[0xFF46] = 0;
+    //ram[0xFF41] = 255;
+    //ram[0xFF45] = 1;
+
+    if let Some(path) = config.record_audio {
+        mem.apu
+            .start_recording(path, config.record_channels)
+            .unwrap_or_else(|e| {
+                println!("Error: {}", e);
+                process::exit(1);
+            });
+    }
+
+    let mut pacer = pacing::Pacer::new(config.video_sync, config.framerate, &window);
+
+    while window.update() {
+        window.clear();
+        controls.get_keyboard(&config, &mut cpu, &mut mem, &mut window);
+        controls.update_ram(&mut mem);
+        window.push_matrix(&gpu.screen, &mut texture);
+        for event in mem.cartridge.events.drain(..) {
+            match event {
+                cartridge::CartridgeEvent::Rumble(on) => window.set_rumble(on),
+                // Nothing to talk to over IR yet
+                cartridge::CartridgeEvent::Infrared(on) => {
+                    if config.debug >= 1 {
+                        println!("IR LED: {}", if on { "on" } else { "off" });
+                    }
+                }
+            }
+        }
+        window.draw_rumble();
+        if let Some(player) = &mut player {
+            // Right and left arrows change tracks, R restarts the current one
+            for keycode in &window.pressed_keys {
+                match *keycode {
+                    Keycode::Right => player.next(),
+                    Keycode::Left => player.previous(),
+                    Keycode::R => {}
+                    _ => continue,
+                }
+                player.play(config.model, &mut cpu, &mut mem);
+            }
+        }
+        if cpu.is_stopped {
+            // The LCD is stopped too, keep the last frame and wait for a button
+            master.step(&mut cpu, &mut controls, &mut mem);
+            master.sync_link(&mut mem, 154);
+            thread::sleep(Duration::from_millis(16));
+            continue;
+        }
+        for _ in 0..pacer.frames_due() {
+            master.screen(&mut cpu, &mut gpu, &mut controls, &mut mem);
+            //println!("frame");
+            gpu.build_bg(&mem);
+            gpu.build_window(&mem);
+            gpu.build_sprite(&mem);
+        }
+        window.draw_scope(&mem.apu);
+        window.queue_audio(&mem.apu.samples);
+        mem.apu.samples.clear();
+        mem.apu.set_sample_rate(pacer.sample_rate(&window));
+        pacer.wait(&window);
+    }
+
+    // The WAV headers are only complete once the recording is stopped
+    if let Err(e) = mem.apu.stop_recording() {
+        eprintln!("Warning: unable to finish the recording ({})", e);
     }
 
-    let mut controls: controls::Controls = controls::Controls {
+    if let Some(data) = mem.cartridge.save_data() {
+        file_io::write_save(&config, &data);
+    }
+}
+
+// CPU, PPU, joypad and scheduler in their power up state, or where the boot ROM leaves them
+// when there is none
+fn power_on(
+    config: &Config,
+    mem: &mut memory::Memory,
+) -> (hardware::Cpu, hardware::Gpu, controls::Controls, master::Master) {
+    let controls: controls::Controls = controls::Controls {
         up: 0,
         down: 0,
         left: 0,
@@ -83,15 +282,11 @@ fn main() {
         b: 0,
         select: 0,
         start: 0,
+        tilt_x: 0.0,
+        tilt_y: 0.0,
     };
 
-    let mut window: gui::Gui = gui::Gui::new(&config);
-    let creator = window.canvas.texture_creator();
-    let mut texture = creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 160, 144)
-        .expect("Couldn't create texture");
-
-    let mut gpu: hardware::Gpu = hardware::Gpu {
+    let gpu: hardware::Gpu = hardware::Gpu {
         screen: [[0; 144]; 160],
         bg_matrix: [[0; 256]; 256],
         window_matrix: [[0; 256]; 256],
@@ -108,57 +303,141 @@ fn main() {
         e: 0,
         h: 0,
         l: 0,
-        sp: 0,     //0xfffe, // default value
-        pc: 0x100, //default valueS
+        sp: 0,
+        pc: 0,
         mie: true,
         pending_mie: None,
         pending_ticks: 0,
         is_halted: false,
+        halt_bug: false,
+        is_stopped: false,
+        is_locked: false,
     };
 
-    let mut timer: timer::Timer = timer::Timer {
-        divider_ticks: 0, //update every 256
-        division: 0,
-        timer_ticks: 0, //Update every division
-        timer_enb: false,
-    };
+    // Without a boot ROM, start the game where the boot ROM would have left off
+    if !mem.boot_rom_mapped() {
+        boot::init_registers(config.model, &mut cpu, mem.cartridge.header.header_checksum);
+        boot::init_io(config.model, mem);
+    }
 
-    let mut master: master::Master = master::Master {
+    let master: master::Master = master::Master {
         nb_steps: 0,
         tick: 0,
+        tick_remainder: 0,
         mode: PX_TRANSFER,
         previous_mode: PX_TRANSFER,
         step_by_step: false,
         line_by_line: false,
         screen_by_screen: false,
         log: false,
+        break_on_illegal: config.break_on_illegal,
+        history: VecDeque::new(),
     };
-    //ram[0xff05] = 255;
-    //ram[0xffff] = 0;
-    //ram[0xff0f] = 0;
-    //ram[0xFF46] = 0;
-    //ram[0xFF41] = 255;
-    //ram[0xFF45] = 1;
 
-    let mut frm = FPSManager::new();
-    if config.framerate > 0 {
-        frm.set_framerate(config.framerate)
-            .expect("Couldn't set framerate");
+    (cpu, gpu, controls, master)
+}
+
+fn get_model(matches: &clap::ArgMatches) -> boot::Model {
+    match matches.value_of("model") {
+        Some(name) => boot::Model::from_name(name).unwrap_or_else(|| {
+            eprintln!("Warning: \"{}\" is not a valid model, defaulted to DMG.", name);
+            boot::Model::Dmg
+        }),
+        None => boot::Model::Dmg,
     }
+}
 
+// "info" subcommand: decode the header without starting the emulator
+fn info(matches: &clap::ArgMatches) {
+    let config = Config {
+        rom_path: Path::new(matches.value_of("ROM").unwrap()),
+        debug: 0,
+        full_screen: false,
+        framerate: 0.0,
+        video_sync: false,
+        camera_source: None,
+        zip_entry: matches.value_of("zip_entry"),
+        patch_path: matches.value_of("patch").map(Path::new),
+        boot_rom_path: None,
+        model: boot::Model::Dmg,
+        break_on_illegal: false,
+        record_audio: None,
+        record_channels: false,
+        scope: false,
+        print_serial: false,
+        link_host: None,
+        link_connect: None,
+    };
 
-    while window.update() {
-        window.clear();
-        controls.get_keyboard(&config, &mut cpu, &mut mem, &mut window);
-        controls.update_ram(&mut mem);
-        window.push_matrix(&gpu.screen, &mut texture);
-        master.screen(&mut cpu, &mut gpu, &mut timer, &mut controls, &mut mem);
-        //println!("frame");
-        gpu.build_bg(&mem);
-        gpu.build_window(&mem);
-        gpu.build_sprite(&mem);
-        //if config.framerate > 0 {
-        //    frm.delay();
-        //}
+    let banks = file_io::load_rom(&config).unwrap_or_else(|e| {
+        println!("Error: {}", e);
+        process::exit(1);
+    });
+    let header = cartridge::CartridgeHeader::from_rom(&banks);
+    if matches.is_present("json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&header).expect("Unable to serialize header")
+        );
+    } else {
+        println!("{}", header);
+    }
+}
+
+// "render" subcommand: run a GBS track as fast as possible and record it
+fn render(matches: &clap::ArgMatches) {
+    let config = Config {
+        rom_path: Path::new(matches.value_of("GBS").unwrap()),
+        debug: 0,
+        full_screen: false,
+        framerate: 0.0,
+        video_sync: false,
+        camera_source: None,
+        zip_entry: None,
+        patch_path: None,
+        boot_rom_path: None,
+        model: get_model(matches),
+        break_on_illegal: false,
+        record_audio: matches.value_of("OUTPUT").map(Path::new),
+        record_channels: matches.is_present("record_channels"),
+        scope: false,
+        print_serial: false,
+        link_host: None,
+        link_connect: None,
+    };
+
+    let exit = |e: String| -> ! {
+        println!("Error: {}", e);
+        process::exit(1);
+    };
+    let gbs = match file_io::load_gbs(&config) {
+        Ok(Some(gbs)) => gbs,
+        Ok(None) => exit(format!("{} is not a GBS file", config.rom_path.display())),
+        Err(e) => exit(e),
+    };
+    let track = match matches.value_of("track") {
+        Some(track) => match track.parse::<u8>() {
+            Ok(track) if track >= 1 && track <= gbs.songs => track - 1,
+            _ => exit(format!("track must be between 1 and {}", gbs.songs)),
+        },
+        None => gbs.first_track(),
+    };
+    let seconds = value_t!(matches, "seconds", f64).unwrap_or_else(|e| match e.kind {
+        clap::ErrorKind::ArgumentNotFound => 120.0,
+        _ => e.exit(),
+    });
+
+    let mut mem = memory::Memory::new(&config).unwrap_or_else(|e| exit(e));
+    let (mut cpu, mut gpu, mut controls, mut master) = power_on(&config, &mut mem);
+    gbs.start_track(track, config.model, &mut cpu, &mut mem);
+
+    mem.apu
+        .start_recording(config.record_audio.unwrap(), config.record_channels)
+        .unwrap_or_else(|e| exit(e));
+    for _ in 0..(seconds * pacing::FRAME_RATE).round() as u64 {
+        master.screen(&mut cpu, &mut gpu, &mut controls, &mut mem);
+        // Only the recording is needed
+        mem.apu.samples.clear();
     }
+    mem.apu.stop_recording().unwrap_or_else(|e| exit(e));
 }
@@ -1,51 +1,81 @@
-use crate::{controls, dma, hardware, instructions, interrupts, memory, timer};
+use crate::{controls, dma, hardware, instructions, interrupts, memory};
 use memory::Memory;
+use std::collections::VecDeque;
 use std::io::{stdin, stdout, Read, Write};
 
 const H_BLANK: u8 = 0;
 const V_BLANK: u8 = 1;
 const PX_TRANSFER: u8 = 2;
 
+// Instructions kept for the illegal opcode report
+const HISTORY_LEN: usize = 16;
+
 pub struct Master {
     pub nb_steps: u64,
     pub tick: u64,
+    pub tick_remainder: u8, // Ticks not yet seen by the LCD in double speed mode
     pub mode: u8,
     pub previous_mode: u8,
     pub step_by_step: bool,
     pub line_by_line: bool,
     pub screen_by_screen: bool,
     pub log: bool,
+    pub break_on_illegal: bool,
+    pub history: VecDeque<(u16, usize, u8)>, // PC, ROM bank, opcode
 }
 
 impl Master {
     pub fn step(
         &mut self,
         cpu: &mut hardware::Cpu,
-        timer: &mut timer::Timer,
         controls: &mut controls::Controls,
         mem: &mut Memory,
     ) {
         self.nb_steps += 1;
 
+        if cpu.is_stopped {
+            // Nothing runs until a selected joypad line goes low
+            controls.update_ram(mem);
+            if mem.read(0xFF00) & 0x0F != 0x0F {
+                cpu.is_stopped = false;
+            }
+            return;
+        }
+
+        if cpu.is_locked {
+            // The CPU is hung for good, the rest of the hardware keeps running
+            self.idle(controls, mem);
+            return;
+        }
+
+        cpu.clear_ticks();
+
         let interrupt_occured = interrupts::interrupt_check(cpu, mem);
 
-        if interrupt_occured {
+        if interrupt_occured && cpu.is_halted {
+            // Waking up from HALT takes an extra M-cycle
             cpu.is_halted = false;
+            self.idle(controls, mem);
         }
 
         if cpu.is_halted {
-            // Is this correct ? I have no idea, but it work
-            self.tick = self.tick.wrapping_add(1);
-            timer.update(4, mem);
-            controls.update_ram(mem);
+            self.idle(controls, mem);
             return;
         }
 
-        cpu.clear_ticks();
-
         let instruct =
             instructions::Instruct::fetch(cpu, mem.read(cpu.pc), mem.read(cpu.pc.wrapping_add(1)));
-        cpu.pc = cpu.pc.wrapping_add(1);
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history
+            .push_back((cpu.pc, mem.cartridge.rom_bank(cpu.pc), instruct.opcode));
+        if cpu.halt_bug {
+            // The byte after HALT is read twice
+            cpu.halt_bug = false;
+        } else {
+            cpu.pc = cpu.pc.wrapping_add(1);
+        }
 
         // println!("Step: {:#08}, PC: {:#06x}, OPCODE:{:#04x} => {:#04x} | {:#04x} | {:#04x} ({})", self.nb_steps, cpu.pc, instruct.opcode,
         //     mem.read(cpu.pc + 0), mem.read(cpu.pc + 1), mem.read(cpu.pc + 2), instruct.inst,
@@ -53,42 +83,87 @@ impl Master {
 
         if self.step_by_step {
             self.log = true;
-            self.maxi_debug_print(&cpu, &timer, &mem, &controls, &instruct);
+            self.maxi_debug_print(&cpu, &mem, &controls, &instruct);
             wait();
         }
 
-        self.tick = self.tick.wrapping_add((instruct.ticks as u64)/4);//Stupid solution to a stupid problem
-        self.tick = self.tick.wrapping_add((cpu.get_ticks()) as u64);
+        cpu.update_interrupt_status(); // If EI was the previous instruction
 
-        timer.update(instruct.ticks, mem);
+        instruct.inst.exec(cpu, mem);
 
-        controls.update_ram(mem);
+        if cpu.is_locked {
+            self.illegal_opcode_report(cpu, mem);
+            if self.break_on_illegal {
+                // Continue step by step, the prompt comes right after this step
+                self.step_by_step = true;
+                self.log = true;
+                self.maxi_debug_print(cpu, mem, controls, &instruct);
+            }
+        }
 
-        cpu.update_interrupt_status(); // If instruction from last step wants to change MIE
+        // The instruction, plus taken branches and interrupt dispatch
+        let ticks = instruct.ticks + cpu.get_ticks();
 
-        instruct.inst.exec(cpu, mem);
+        self.advance_lcd(mem, ticks);
+
+        if mem.timer.update(ticks) {
+            mem.write(0xFF0F, mem.read(0xFF0F) | 0b00000100);
+        }
+        if mem.serial.update(mem.timer.divider, ticks) {
+            mem.write(0xFF0F, mem.read(0xFF0F) | 0b00001000);
+        }
+        mem.cartridge.update(ticks);
+        controls.update_ram(mem);
+        dma::update_dma(mem, ticks / 4);
+    }
 
-        //adding temporary ticks from the cpu
+    // In CGB double speed mode, the LCD and APU see half as many cycles. KEY1 is only mapped
+    // on CGB, its speed bit is set by STOP.
+    fn advance_lcd(&mut self, mem: &mut Memory, ticks: u8) {
+        let double_speed = mem.model.is_cgb() && mem.ram[0x7F4D] & 0x80 > 0;
+        let speed = if double_speed { 8 } else { 4 };
+        let lcd_ticks = self.tick_remainder + ticks;
+        self.tick = self.tick.wrapping_add((lcd_ticks / speed) as u64);
+        self.tick_remainder = lcd_ticks % speed;
+        mem.apu.update(ticks * 4 / speed);
+    }
+
+    // One M-cycle without the CPU doing anything
+    fn idle(&mut self, controls: &mut controls::Controls, mem: &mut Memory) {
+        self.advance_lcd(mem, 4);
+        if mem.timer.update(4) {
+            mem.write(0xFF0F, mem.read(0xFF0F) | 0b00000100);
+        }
+        if mem.serial.update(mem.timer.divider, 4) {
+            mem.write(0xFF0F, mem.read(0xFF0F) | 0b00001000);
+        }
+        controls.update_ram(mem);
+        dma::update_dma(mem, 1);
+    }
 
-        dma::update_dma(mem);
+    // The mode is also seen by the CPU in the low bits of STAT. PX_TRANSFER stands for both the
+    // OAM search and the pixel transfer.
+    fn set_mode(&mut self, mode: u8, mem: &mut Memory) {
+        self.mode = mode;
+        mem.ram[0x7F41] = (mem.ram[0x7F41] & 0b1111_1100) | mode;
     }
 
     pub fn screen(
         &mut self,
         cpu: &mut hardware::Cpu,
         gpu: &mut hardware::Gpu,
-        timer: &mut timer::Timer,
         controls: &mut controls::Controls,
         mem: &mut Memory,
     ) {
-        mem.write(0xFF44, 0);
+        // LY is read only for the CPU, update it directly
+        mem.ram[0x7F44] = 0;
         for i in 0..144 {
-            mem.write(0xff44, mem.read(0xff44) + 1);
+            mem.ram[0x7F44] += 1;
             while self.tick < 114 {
                 if self.tick > 63 {
-                    self.mode = H_BLANK;
+                    self.set_mode(H_BLANK, mem);
                 } else {
-                    self.mode = PX_TRANSFER;
+                    self.set_mode(PX_TRANSFER, mem);
                 }
                 //print!("{esc}c", esc = 27 as char);
                 //println!("SCREEN STATE__________________________________");
@@ -96,24 +171,28 @@ impl Master {
                 //println!("Line: {}",i);
                 //println!("Mode: {}",self.mode);
                 //println!(" ");
-                self.step(cpu, timer, controls, mem);
+                self.step(cpu, controls, mem);
+                if cpu.is_stopped {
+                    // The LCD stops with the CPU, the frontend waits for a button
+                    self.tick = 0;
+                    return;
+                }
                 self.lcd_stat(i, mem);
                 if self.step_by_step {
                     wait();
                 }
             }
             self.tick = 0;
+            self.sync_link(mem, 1);
             gpu.push_line(mem);
 
             if self.line_by_line {
                 wait();
             }
-
-
         }
 
         mem.write(0xFF0F, mem.read(0xFF0F) | 0b1);
-        self.mode = V_BLANK;
+        self.set_mode(V_BLANK, mem);
 
         for _j in 0..10 {
             while self.tick < 114 {
@@ -122,17 +201,22 @@ impl Master {
                 //println!("State: V-Blank");
                 //println!("Mode: {}",self.mode);
                 //println!(" ");
-                self.step(cpu, timer, controls, mem);
+                self.step(cpu, controls, mem);
+                if cpu.is_stopped {
+                    self.tick = 0;
+                    return;
+                }
                 self.lcd_stat(254, mem);
                 if self.step_by_step {
                     wait();
                 }
             }
             self.tick = 0;
+            self.sync_link(mem, 1);
             if self.line_by_line {
                 wait();
             }
-            mem.write(0xff44, mem.read(0xff44) + 1);
+            mem.ram[0x7F44] += 1;
         }
 
         if self.screen_by_screen {
@@ -140,10 +224,16 @@ impl Master {
         }
     }
 
+    // Lets the other Game Boy on the link cable catch up, lines are the time unit
+    pub fn sync_link(&mut self, mem: &mut Memory, lines: u64) {
+        if mem.serial.sync(lines) {
+            mem.write(0xFF0F, mem.read(0xFF0F) | 0b00001000);
+        }
+    }
+
     pub fn maxi_debug_print(
         &self,
         cpu: &hardware::Cpu,
-        timer: &timer::Timer,
         mem: &Memory,
         controls: &controls::Controls,
         instruct: &instructions::Instruct,
@@ -191,12 +281,10 @@ impl Master {
             println!("C:{}", cpu.get_flag(hardware::Flag::C));
             println!();
             println!("TIMER STATE__________________________________");
-            println!("Divider:{:#04x}", mem.read(0xff04));
-            println!("Divider ticks:{}", timer.divider_ticks);
-            println!("Timer enable:{}", timer.timer_enb);
-            println!("Timer division:{}", timer.division);
-            println!("Timer:{:#04x}", mem.read(0xff05));
-            println!("Timer ticks:{}", timer.timer_ticks);
+            println!("Divider:{:#06x}", mem.timer.divider);
+            println!("Timer:{:#04x}", mem.timer.tima);
+            println!("Timer modulo:{:#04x}", mem.timer.tma);
+            println!("Timer control:{:#05b}", mem.timer.tac);
             println!();
             println!("INPUT STATE__________________________________");
             println!(
@@ -216,6 +304,41 @@ impl Master {
         }
     }
 
+    // An illegal opcode almost always means the game jumped into garbage:
+    // show how it got there
+    fn illegal_opcode_report(&self, cpu: &hardware::Cpu, mem: &Memory) {
+        let location = |addr: u16| -> String {
+            if addr < 0x8000 {
+                format!("{:#06x} (bank {})", addr, mem.cartridge.rom_bank(addr))
+            } else {
+                format!("{:#06x}", addr)
+            }
+        };
+
+        println!(
+            "Illegal opcode {:#04x} at {}, the CPU is locked up",
+            mem.read(cpu.pc),
+            location(cpu.pc)
+        );
+        println!("Last instructions:");
+        for (pc, bank, opcode) in &self.history {
+            if *pc < 0x8000 {
+                println!("  {:#06x} (bank {}): {:#04x}", pc, bank, opcode);
+            } else {
+                println!("  {:#06x}: {:#04x}", pc, opcode);
+            }
+        }
+        println!("Stack (SP = {:#06x}):", cpu.sp);
+        for i in 0..8 {
+            let addr = cpu.sp.wrapping_add(i * 2);
+            if addr < cpu.sp {
+                break;
+            }
+            let value = ((mem.read(addr.wrapping_add(1)) as u16) << 8) | mem.read(addr) as u16;
+            println!("  {:#06x}: {}", addr, location(value));
+        }
+    }
+
     pub fn lcd_stat(&mut self, line: u8, mem: &mut Memory) {
 
         if  mem.read(0xFF41) & 0b01000000 > 0
@@ -259,3 +382,60 @@ pub fn wait() {
     stdin().read(&mut [0]).unwrap();
     print!("{esc}c", esc = 27 as char);
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::power_on_program;
+
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        // HALT, INC A, NOP with IME off and the timer interrupt pending
+        let (mut cpu, mut controls, mut master, mut mem) =
+            power_on_program("halt_bug", &[0x76, 0x3C, 0x00]);
+        cpu.mie = false;
+        cpu.a = 0;
+        mem.write(0xFFFF, 0x04);
+        mem.write(0xFF0F, 0x04);
+
+        for _ in 0..3 {
+            master.step(&mut cpu, &mut controls, &mut mem);
+        }
+        assert!(!cpu.is_halted);
+        assert_eq!(cpu.a, 2);
+        assert_eq!(cpu.pc, 0x0102);
+    }
+
+    #[test]
+    fn stop_returns_from_screen_until_a_button_is_pressed() {
+        // STOP, INC A
+        let (mut cpu, mut controls, mut master, mut mem) =
+            power_on_program("stop", &[0x10, 0x00, 0x3C]);
+        let mut gpu = crate::hardware::Gpu {
+            screen: [[0; 144]; 160],
+            bg_matrix: [[0; 256]; 256],
+            window_matrix: [[0; 256]; 256],
+            sprite_matrix: [[0; 256]; 256],
+            line: 0,
+        };
+        cpu.a = 0;
+        controls.start = 1;
+        controls.select = 1;
+        controls.b = 1;
+        controls.a = 1;
+        mem.write(0xFF00, 0x10); // Buttons selected
+
+        master.screen(&mut cpu, &mut gpu, &mut controls, &mut mem);
+        assert!(cpu.is_stopped);
+        assert_eq!(cpu.pc, 0x0102);
+
+        // Still stopped without a button
+        master.step(&mut cpu, &mut controls, &mut mem);
+        assert!(cpu.is_stopped);
+
+        controls.start = 0;
+        master.step(&mut cpu, &mut controls, &mut mem);
+        assert!(!cpu.is_stopped);
+        master.screen(&mut cpu, &mut gpu, &mut controls, &mut mem);
+        assert_eq!(cpu.a, 1);
+    }
+}
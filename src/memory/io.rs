@@ -0,0 +1,124 @@
+use super::Memory;
+
+// Sources:
+// https://gbdev.io/pandocs/Hardware_Reg_List.html
+// https://github.com/Gekkio/mooneye-test-suite (unused_hwio test)
+//
+// Most I/O registers are stored in Memory.ram (0x7F00~0x7F7F), so the hardware can update
+// their read only bits directly. The serial, timer and APU registers live in their own modules.
+// Only CPU accesses go through the masks below.
+
+struct Register {
+    unused: u8,   // Bits that always read as 1
+    writable: u8, // Bits the CPU can change
+}
+
+const fn reg(unused: u8, writable: u8) -> Option<Register> {
+    Some(Register { unused, writable })
+}
+
+// None for unmapped addresses, which read as 0xFF and ignore writes
+fn register(addr: u16, cgb: bool) -> Option<Register> {
+    match addr {
+        0xFF00 => reg(0xC0, 0x30),          // P1, the buttons are read only
+        0xFF01 => reg(0x00, 0xFF),          // SB
+        0xFF02 if cgb => reg(0x7C, 0x83),   // SC, with the CGB fast clock bit
+        0xFF02 => reg(0x7E, 0x81),          // SC
+        0xFF04 => reg(0x00, 0x00),          // DIV, any write resets it
+        0xFF05 => reg(0x00, 0xFF),          // TIMA
+        0xFF06 => reg(0x00, 0xFF),          // TMA
+        0xFF07 => reg(0xF8, 0x07),          // TAC
+        0xFF0F => reg(0xE0, 0x1F),          // IF
+        0xFF10 => reg(0x80, 0x7F),          // NR10
+        0xFF11 => reg(0x3F, 0xFF),          // NR11, the length is write only
+        0xFF12 => reg(0x00, 0xFF),          // NR12
+        0xFF13 => reg(0xFF, 0xFF),          // NR13, write only
+        0xFF14 => reg(0xBF, 0xC7),          // NR14, only the length enable can be read
+        0xFF16 => reg(0x3F, 0xFF),          // NR21
+        0xFF17 => reg(0x00, 0xFF),          // NR22
+        0xFF18 => reg(0xFF, 0xFF),          // NR23
+        0xFF19 => reg(0xBF, 0xC7),          // NR24
+        0xFF1A => reg(0x7F, 0x80),          // NR30
+        0xFF1B => reg(0xFF, 0xFF),          // NR31
+        0xFF1C => reg(0x9F, 0x60),          // NR32
+        0xFF1D => reg(0xFF, 0xFF),          // NR33
+        0xFF1E => reg(0xBF, 0xC7),          // NR34
+        0xFF20 => reg(0xFF, 0x3F),          // NR41
+        0xFF21 => reg(0x00, 0xFF),          // NR42
+        0xFF22 => reg(0x00, 0xFF),          // NR43
+        0xFF23 => reg(0xBF, 0xC0),          // NR44
+        0xFF24 => reg(0x00, 0xFF),          // NR50
+        0xFF25 => reg(0x00, 0xFF),          // NR51
+        0xFF26 => reg(0x70, 0x80),          // NR52, the channel status bits are read only
+        0xFF30..=0xFF3F => reg(0x00, 0xFF), // Wave RAM
+        0xFF40 => reg(0x00, 0xFF),          // LCDC
+        0xFF41 => reg(0x80, 0x78),          // STAT, mode and LY=LYC are read only
+        0xFF42 => reg(0x00, 0xFF),          // SCY
+        0xFF43 => reg(0x00, 0xFF),          // SCX
+        0xFF44 => reg(0x00, 0x00),          // LY
+        0xFF45 => reg(0x00, 0xFF),          // LYC
+        0xFF46 => reg(0x00, 0xFF),          // DMA
+        0xFF47 => reg(0x00, 0xFF),          // BGP
+        0xFF48 => reg(0x00, 0xFF),          // OBP0
+        0xFF49 => reg(0x00, 0xFF),          // OBP1
+        0xFF4A => reg(0x00, 0xFF),          // WY
+        0xFF4B => reg(0x00, 0xFF),          // WX
+        0xFF50 => reg(0xFF, 0xFF),          // Boot ROM unmapping, write only
+        // CGB only
+        0xFF4D if cgb => reg(0x7E, 0x01), // KEY1, the current speed is read only
+        0xFF4F if cgb => reg(0xFE, 0x01), // VBK
+        0xFF51..=0xFF54 if cgb => reg(0xFF, 0xFF), // HDMA1~HDMA4, write only
+        0xFF55 if cgb => reg(0x00, 0xFF), // HDMA5
+        0xFF56 if cgb => reg(0x3C, 0xC1), // RP, the received bit is read only
+        0xFF68 if cgb => reg(0x40, 0xBF), // BCPS
+        0xFF69 if cgb => reg(0x00, 0xFF), // BCPD
+        0xFF6A if cgb => reg(0x40, 0xBF), // OCPS
+        0xFF6B if cgb => reg(0x00, 0xFF), // OCPD
+        0xFF6C if cgb => reg(0xFE, 0x01), // OPRI
+        0xFF70 if cgb => reg(0xF8, 0x07), // SVBK
+        0xFF72..=0xFF74 if cgb => reg(0x00, 0xFF),
+        0xFF75 if cgb => reg(0x8F, 0x70),
+        0xFF76 | 0xFF77 if cgb => reg(0x00, 0x00), // PCM12, PCM34
+        _ => None,
+    }
+}
+
+impl Memory {
+    pub(super) fn read_io(&self, addr: u16) -> u8 {
+        match register(addr, self.model.is_cgb()) {
+            Some(register) => {
+                let value = match addr {
+                    0xFF01..=0xFF02 => self.serial.read(addr),
+                    0xFF04..=0xFF07 => self.timer.read(addr),
+                    0xFF10..=0xFF3F => self.apu.read(addr),
+                    _ => self.ram[(addr & 0x7FFF) as usize],
+                };
+                value | register.unused
+            }
+            None => 0xFF,
+        }
+    }
+
+    pub(super) fn write_io(&mut self, addr: u16, data: u8) {
+        let register = match register(addr, self.model.is_cgb()) {
+            Some(register) => register,
+            None => return,
+        };
+        let index = (addr & 0x7FFF) as usize;
+        self.ram[index] = (self.ram[index] & !register.writable) | (data & register.writable);
+
+        match addr {
+            // SB and SC live in the serial port
+            0xFF01..=0xFF02 => self.serial.write(addr, data & register.writable),
+            // DIV, TIMA, TMA and TAC live in the timer
+            0xFF04..=0xFF07 => self.timer.write(addr, data & register.writable),
+            // Sound registers and wave RAM live in the APU
+            0xFF10..=0xFF3F => self.apu.write(addr, data & register.writable),
+            // Starts an OAM DMA transfer, the register keeps the page
+            0xFF46 => self.dma.request(data),
+            // Unmaps the boot ROM for good
+            0xFF50 if data & 0x01 > 0 => self.boot_rom = Vec::new(),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::apu;
+use crate::gui::Gui;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Sources:
+// https://docs.libretro.com/development/cores/dynamic-rate-control/
+// https://near.sh/articles/audio/dynamic-rate-control
+
+// 70224 cycles per frame at 4.194304 MHz, not quite 60 Hz
+pub const FRAME_RATE: f64 = 4_194_304.0 / 70_224.0;
+
+// Audio kept queued, in samples per channel: enough to ride out a late frame (50ms)
+const TARGET_LATENCY: u32 = apu::SAMPLE_RATE / 20;
+
+// Largest adjustment of the sample rate, too small to hear as a change of pitch
+const MAX_RATE_DELTA: f64 = 0.005;
+
+// When this far behind (window dragged, debugger pause...), give up catching up
+const MAX_CATCH_UP: u64 = 4;
+
+pub struct Pacer {
+    audio_sync: bool,
+    video_sync: bool,
+    framerate: f64,
+    start: Instant,
+    frames: u64,
+}
+
+impl Pacer {
+    // Audio sync only works at the real frame rate, anything else is paced by the clock
+    pub fn new(video_sync: bool, framerate: f64, gui: &Gui) -> Pacer {
+        let audio_sync = !video_sync && gui.audio.is_some() && framerate == FRAME_RATE;
+        Pacer {
+            audio_sync,
+            video_sync,
+            framerate,
+            start: Instant::now(),
+            frames: 0,
+        }
+    }
+
+    // Frames to emulate before the next screen refresh. With video sync, that's however many
+    // frames are due since the last refresh: mostly 1 at 60 Hz, sometimes 0 at 144 Hz.
+    pub fn frames_due(&mut self) -> u64 {
+        if self.audio_sync || self.framerate == 0.0 {
+            return 1;
+        }
+        let due = (self.start.elapsed().as_secs_f64() * self.framerate) as u64;
+        if due > self.frames + MAX_CATCH_UP {
+            self.frames = due - 1;
+        }
+        let count = due.saturating_sub(self.frames);
+        self.frames += count;
+        count
+    }
+
+    // Audio sync: the emulator runs ahead until the audio queue is full enough, then waits for
+    // it to play back, which makes it run at exactly the speed of the audio clock.
+    // Clock pacing without vsync sleeps until the next frame is due.
+    pub fn wait(&self, gui: &Gui) {
+        if self.audio_sync {
+            while gui.queued_audio() > TARGET_LATENCY {
+                thread::sleep(Duration::from_millis(1));
+            }
+        } else if !self.video_sync && self.framerate > 0.0 {
+            let next_frame = Duration::from_secs_f64((self.frames + 1) as f64 / self.framerate);
+            if let Some(delay) = next_frame.checked_sub(self.start.elapsed()) {
+                thread::sleep(delay);
+            }
+        }
+    }
+
+    // Dynamic rate control: make slightly more samples when the queue runs low and fewer when it
+    // fills up, so it stays around the target latency without crackles or drift
+    pub fn sample_rate(&self, gui: &Gui) -> u32 {
+        let fill = (gui.queued_audio() as f64 / (2 * TARGET_LATENCY) as f64).min(1.0);
+        let ratio = 1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill);
+        (apu::SAMPLE_RATE as f64 * ratio) as u32
+    }
+}
@@ -0,0 +1,350 @@
+use flate2::Crc;
+use std::fs;
+use std::path::Path;
+
+// Sources:
+// https://zerosoft.zophar.net/ips.php
+// https://github.com/blakesmith/rombp/blob/master/docs/bps_spec.md
+// https://www.romhacking.net/documents/392/ (UPS)
+
+pub fn apply(patch_path: &Path, rom: &[u8]) -> Result<Vec<u8>, String> {
+    let patch = fs::read(patch_path).map_err(|e| e.to_string())?;
+
+    if patch.starts_with(b"PATCH") {
+        apply_ips(&patch, rom)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(&patch, rom)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(&patch, rom)
+    } else {
+        Err("unknown patch format".to_string())
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn read_u32_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// Offsets and sizes that overflow, or runs going past the target size
+const INVALID: &str = "patch is invalid";
+
+// Reads bytes from the patch, failing cleanly instead of panicking on truncated patches
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.data.get(self.pos).ok_or("patch is truncated")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).ok_or(INVALID)?;
+        let bytes = self.data.get(self.pos..end).ok_or("patch is truncated")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16_be(&mut self) -> Result<usize, String> {
+        Ok(((self.byte()? as usize) << 8) | self.byte()? as usize)
+    }
+
+    fn u24_be(&mut self) -> Result<usize, String> {
+        Ok(((self.byte()? as usize) << 16) | self.u16_be()?)
+    }
+
+    // Variable length number used by BPS and UPS: 7 bits per byte, the last one has bit 7 set
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value = 0;
+        let mut shift = 1;
+        loop {
+            let byte = self.byte()?;
+            value = ((byte & 0x7F) as usize)
+                .checked_mul(shift)
+                .and_then(|bits| bits.checked_add(value))
+                .ok_or(INVALID)?;
+            if byte & 0x80 > 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(INVALID)?;
+            value = value.checked_add(shift).ok_or(INVALID)?;
+        }
+    }
+}
+
+// BPS and UPS end with the CRC32 of the source, of the target and of the patch itself
+fn check_footer(patch: &[u8], rom: &[u8]) -> Result<(u32, usize), String> {
+    if patch.len() < 16 {
+        return Err("patch is truncated".to_string());
+    }
+    let footer = patch.len() - 12;
+    if crc32(&patch[..patch.len() - 4]) != read_u32_le(&patch[footer + 8..]) {
+        return Err("patch is corrupted (bad patch checksum)".to_string());
+    }
+    if crc32(rom) != read_u32_le(&patch[footer..]) {
+        return Err("patch was made for another ROM (bad source checksum)".to_string());
+    }
+    Ok((read_u32_le(&patch[footer + 4..]), footer))
+}
+
+fn check_target(target: &[u8], crc: u32) -> Result<(), String> {
+    if crc32(target) != crc {
+        return Err("patched ROM is invalid (bad target checksum)".to_string());
+    }
+    Ok(())
+}
+
+// IPS: records of 3-byte offset, 2-byte size and data until "EOF". A size of 0 means an RLE
+// record (2-byte count, 1 byte value). An optional 3-byte size after "EOF" truncates the ROM.
+fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    let mut target = rom.to_vec();
+    let mut reader = Reader {
+        data: patch,
+        pos: 5,
+    };
+
+    loop {
+        if reader.data.get(reader.pos..reader.pos + 3) == Some(b"EOF") {
+            reader.pos += 3;
+            break;
+        }
+        let offset = reader.u24_be()?;
+        let size = reader.u16_be()?;
+
+        let (len, data) = if size == 0 {
+            let count = reader.u16_be()?;
+            (count, vec![reader.byte()?; count])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        target[offset..offset + len].copy_from_slice(&data);
+    }
+
+    if let Ok(truncate) = reader.u24_be() {
+        target.truncate(truncate);
+    }
+    Ok(target)
+}
+
+// UPS: the target is the source XORed with runs of bytes, each run placed by a relative offset
+fn apply_ups(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    let (target_crc, footer) = check_footer(patch, rom)?;
+    let mut reader = Reader {
+        data: &patch[..footer],
+        pos: 4,
+    };
+
+    let _source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+
+    let mut pos: usize = 0;
+    while reader.pos < footer {
+        pos = pos.checked_add(reader.varint()?).ok_or(INVALID)?;
+        loop {
+            let byte = reader.byte()?;
+            if byte == 0 {
+                pos += 1;
+                break;
+            }
+            if pos < target_size {
+                target[pos] ^= byte;
+            }
+            pos += 1;
+        }
+    }
+
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+// BPS: the target is built by copying runs from the source, the patch or the target itself
+fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    const SOURCE_READ: usize = 0;
+    const TARGET_READ: usize = 1;
+    const SOURCE_COPY: usize = 2;
+
+    let (target_crc, footer) = check_footer(patch, rom)?;
+    let mut reader = Reader {
+        data: &patch[..footer],
+        pos: 4,
+    };
+
+    let _source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    let invalid = || "patch reads outside of the ROM".to_string();
+
+    // Copy offsets are relative: bit 0 is the sign, the rest the distance
+    let relative = |reader: &mut Reader, offset: isize| -> Result<isize, String> {
+        let data = reader.varint()?;
+        let distance = (data >> 1) as isize;
+        let distance = if data & 1 > 0 { -distance } else { distance };
+        offset
+            .checked_add(distance)
+            .ok_or_else(|| INVALID.to_string())
+    };
+    let range = |start: usize, len: usize| -> Result<std::ops::Range<usize>, String> {
+        Ok(start..start.checked_add(len).ok_or(INVALID)?)
+    };
+
+    while reader.pos < footer {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+
+        match data & 0b11 {
+            SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(rom.get(range(start, len)?).ok_or_else(invalid)?);
+            }
+            TARGET_READ => target.extend_from_slice(reader.bytes(len)?),
+            SOURCE_COPY => {
+                source_offset = relative(&mut reader, source_offset)?;
+                let start = source_offset as usize;
+                if source_offset < 0 {
+                    return Err(invalid());
+                }
+                target.extend_from_slice(rom.get(range(start, len)?).ok_or_else(invalid)?);
+                source_offset += len as isize;
+            }
+            // Target copy, byte by byte as the run may overlap what it is writing
+            _ => {
+                target_offset = relative(&mut reader, target_offset)?;
+                if target.len() + len > target_size {
+                    return Err(INVALID.to_string());
+                }
+                for _ in 0..len {
+                    let byte = *target.get(target_offset as usize).ok_or_else(invalid)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err("patched ROM doesn't have the expected size".to_string());
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_bps, apply_ips, apply_ups, crc32};
+
+    fn varint(mut value: usize, patch: &mut Vec<u8>) {
+        loop {
+            let bits = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                patch.push(0x80 | bits);
+                return;
+            }
+            patch.push(bits);
+            value -= 1;
+        }
+    }
+
+    fn add_footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+    }
+
+    const SOURCE: [u8; 4] = [1, 2, 3, 4];
+
+    // XORs 0x10 and 0x20 onto the bytes 1 and 2 of SOURCE
+    fn ups_patch() -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        varint(4, &mut patch);
+        varint(4, &mut patch);
+        varint(1, &mut patch);
+        patch.extend_from_slice(&[0x10, 0x20, 0x00]);
+        add_footer(&mut patch, &SOURCE, &[1, 0x12, 0x23, 4]);
+        patch
+    }
+
+    #[test]
+    fn ips_record_rle_and_truncation() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x00, 0x06]);
+
+        let target = apply_ips(&patch, &[0; 8]).unwrap();
+        assert_eq!(target, [0x00, 0xAA, 0xBB, 0x00, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn bps_source_and_target_copies() {
+        let target = [3, 4, 9, 3, 4, 9];
+        let mut patch = b"BPS1".to_vec();
+        varint(SOURCE.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        // Source copy of 2 bytes, 2 bytes forward
+        varint((1 << 2) | 2, &mut patch);
+        varint(2 << 1, &mut patch);
+        // Target read of 1 byte
+        varint(1, &mut patch);
+        patch.push(9);
+        // Target copy of 3 bytes from the start
+        varint((2 << 2) | 3, &mut patch);
+        varint(0, &mut patch);
+        add_footer(&mut patch, &SOURCE, &target);
+
+        assert_eq!(apply_bps(&patch, &SOURCE).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_xor() {
+        assert_eq!(
+            apply_ups(&ups_patch(), &SOURCE).unwrap(),
+            [1, 0x12, 0x23, 4]
+        );
+    }
+
+    #[test]
+    fn bad_checksums_rejected() {
+        let mut patch = ups_patch();
+        patch[8] ^= 0xFF;
+        assert!(apply_ups(&patch, &SOURCE).is_err());
+
+        // Made for another ROM
+        assert!(apply_ups(&ups_patch(), &[1, 2, 3, 5]).is_err());
+    }
+
+    #[test]
+    fn truncated_patches_rejected() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x04, 0xAA]);
+        assert_eq!(
+            apply_ips(&patch, &[0; 8]),
+            Err("patch is truncated".to_string())
+        );
+
+        let patch = ups_patch();
+        assert!(apply_ups(&patch[..12], &SOURCE).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use sdl2::gfx::primitives::DrawRenderer;
+use sdl2::pixels::Color;
+use sdl2::rect::Point;
+use sdl2::render::WindowCanvas;
+use sdl2::VideoSubsystem;
+
+use crate::apu::{Apu, SCOPE_LEN};
+
+// Channel viewer: the waveform of each sound channel with its frequency, volume and envelope
+
+const WIDTH: u32 = 512;
+const LANE_HEIGHT: u32 = 100;
+const TEXT_HEIGHT: i32 = 12; // The gfx font is 8x8
+
+const NAMES: [&str; 4] = ["1 Square+sweep", "2 Square", "3 Wave", "4 Noise"];
+const COLORS: [(u8, u8, u8); 4] = [
+    (230, 90, 80),
+    (240, 190, 70),
+    (90, 200, 120),
+    (100, 150, 240),
+];
+
+pub struct Scope {
+    pub canvas: WindowCanvas,
+}
+
+impl Scope {
+    // Opened right of the game window
+    pub fn new(video: &VideoSubsystem, next_to: (i32, i32, u32)) -> Result<Scope, String> {
+        let (x, y, width) = next_to;
+        let window = video
+            .window("Rustboy - Sound channels", WIDTH, LANE_HEIGHT * 4)
+            .position(x + width as i32 + 8, y)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window
+            .into_canvas()
+            .accelerated()
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Scope { canvas })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn draw(&mut self, apu: &Apu) -> Result<(), String> {
+        self.canvas.set_draw_color((16, 16, 24));
+        self.canvas.clear();
+        for channel in 0..4 {
+            self.draw_channel(apu, channel)?;
+        }
+        self.canvas.present();
+        Ok(())
+    }
+
+    fn draw_channel(&mut self, apu: &Apu, channel: usize) -> Result<(), String> {
+        let top = (LANE_HEIGHT as usize * channel) as i32;
+        let (r, g, b) = COLORS[channel];
+        let dimmed = apu.muted[channel];
+        let color = if dimmed {
+            Color::RGB(r / 3, g / 3, b / 3)
+        } else {
+            Color::RGB(r, g, b)
+        };
+
+        let status = apu.channel_status(channel);
+        let envelope = match status.envelope {
+            Some((_, 0)) => " env -".to_string(),
+            Some((increasing, period)) => {
+                format!(" env {}{}", if increasing { '+' } else { '-' }, period)
+            }
+            None => String::new(),
+        };
+        let label = format!(
+            "{}{} {:>8.1} Hz vol {:>2}{} {}{}",
+            NAMES[channel],
+            if status.enabled { "" } else { " (off)" },
+            status.frequency,
+            status.volume,
+            envelope,
+            status.mode,
+            if dimmed { " MUTED" } else { "" }
+        );
+        self.canvas.string(4, top as i16 + 2, &label, color)?;
+
+        // Lane separator
+        self.canvas.set_draw_color((48, 48, 64));
+        self.canvas.draw_line(
+            Point::new(0, top + LANE_HEIGHT as i32 - 1),
+            Point::new(WIDTH as i32, top + LANE_HEIGHT as i32 - 1),
+        )?;
+
+        // Start on a rising edge in the older half so periodic waveforms stand still
+        let samples: Vec<f32> = apu.scope(channel).collect();
+        let start = (1..SCOPE_LEN - WIDTH as usize)
+            .find(|&i| samples[i - 1] < 0.0 && samples[i] >= 0.0)
+            .unwrap_or(SCOPE_LEN - WIDTH as usize);
+        let middle = top + TEXT_HEIGHT + (LANE_HEIGHT as i32 - TEXT_HEIGHT) / 2;
+        let amplitude = (LANE_HEIGHT as i32 - TEXT_HEIGHT) as f32 / 2.0 - 4.0;
+        let points: Vec<Point> = samples[start..start + WIDTH as usize]
+            .iter()
+            .enumerate()
+            .map(|(x, sample)| Point::new(x as i32, middle - (sample * amplitude) as i32))
+            .collect();
+        self.canvas.set_draw_color(color);
+        self.canvas.draw_lines(points.as_slice())
+    }
+}
@@ -0,0 +1,49 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryInto;
+
+// Serde only handles arrays of up to 32 elements, larger ones (RAM, registers) go through
+// #[serde(with = "crate::serde_arrays")] and are stored like slices
+
+pub fn serialize<S: Serializer, T: Serialize, const N: usize>(
+    array: &[T; N],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    array[..].serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(
+    deserializer: D,
+) -> Result<[T; N], D::Error> {
+    let values = Vec::<T>::deserialize(deserializer)?;
+    let len = values.len();
+    values
+        .try_into()
+        .map_err(|_| D::Error::invalid_length(len, &format!("an array of {}", N).as_str()))
+}
+
+// Lists of arrays, like RAM banks
+pub mod vec {
+    use super::*;
+
+    pub fn serialize<S: Serializer, T: Serialize, const N: usize>(
+        arrays: &[[T; N]],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(arrays.iter().map(|array| &array[..]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<Vec<[T; N]>, D::Error> {
+        Vec::<Vec<T>>::deserialize(deserializer)?
+            .into_iter()
+            .map(|values| {
+                let len = values.len();
+                values.try_into().map_err(|_| {
+                    D::Error::invalid_length(len, &format!("an array of {}", N).as_str())
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,195 @@
+use crate::link::Link;
+use serde::{Deserialize, Serialize};
+use std::io::{self, stdout, Write};
+
+// Sources:
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+// https://github.com/Gekkio/mooneye-test-suite (boot_sclk_align)
+//
+// A transfer shifts the 8 bits of SB out, most significant first, while the bits of the other
+// Game Boy are shifted in. With the internal clock the bits are clocked by the falling edges of a
+// divider bit (8192 Hz, or 262144 Hz with the CGB fast clock). With the external clock the other
+// Game Boy drives the transfer, which never ends when nothing is connected.
+//
+// Over a link cable (see link.rs), the byte of the other side replaces the 1s shifted in once
+// the 8 bits are done.
+
+const TRANSFER_START: u8 = 0x80;
+const FAST_CLOCK: u8 = 0x02;
+const INTERNAL_CLOCK: u8 = 0x01;
+
+#[derive(Serialize, Deserialize)]
+pub struct Serial {
+    pub data: u8,    // SB
+    pub control: u8, // SC
+    bits: u8,        // Bits left to shift in the current transfer
+    #[serde(skip)]
+    print: bool, // Echo the bytes sent to stdout
+    #[serde(skip)]
+    pub link: Option<Link>,
+}
+
+impl Serial {
+    pub fn new(print: bool) -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            bits: 0,
+            print,
+            link: None,
+        }
+    }
+
+    // Restores the port from a savestate, the link cable stays plugged in
+    pub fn load_state(&mut self, saved: Serial) {
+        self.data = saved.data;
+        self.control = saved.control;
+        self.bits = saved.bits;
+    }
+
+    // Runs f with the link cable, which is unplugged if the connection fails
+    fn with_link<T: Default>(
+        &mut self,
+        f: impl FnOnce(&mut Serial, &mut Link) -> io::Result<T>,
+    ) -> T {
+        let mut link = match self.link.take() {
+            Some(link) => link,
+            None => return T::default(),
+        };
+        match f(self, &mut link) {
+            Ok(value) => {
+                self.link = Some(link);
+                value
+            }
+            Err(e) => {
+                eprintln!("Warning: link cable disconnected ({})", e);
+                T::default()
+            }
+        }
+    }
+
+    // Called after each scanline. Returns true when the serial interrupt is requested.
+    pub fn sync(&mut self, lines: u64) -> bool {
+        self.with_link(|serial, link| {
+            link.add_lines(lines)?;
+            let mut interrupt = false;
+            loop {
+                interrupt |= serial.receive_transfers(link)?;
+                if !link.ahead() {
+                    return Ok(interrupt);
+                }
+                link.wait()?;
+            }
+        })
+    }
+
+    // Transfers clocked by the other side: with the external clock and a transfer started, the
+    // bytes are swapped. Otherwise nothing is shifted and the other side gets 0xFF.
+    fn receive_transfers(&mut self, link: &mut Link) -> io::Result<bool> {
+        let mut interrupt = false;
+        while let Some(data) = link.next_transfer() {
+            if self.transferring() && self.control & INTERNAL_CLOCK == 0 {
+                link.reply(self.data)?;
+                self.data = data;
+                self.control &= !TRANSFER_START;
+                interrupt = true;
+            } else {
+                link.reply(0xFF)?;
+            }
+        }
+        Ok(interrupt)
+    }
+
+    // End of a transfer clocked by this side
+    fn receive_reply(&mut self, link: &mut Link) -> io::Result<()> {
+        loop {
+            if let Some(data) = link.take_reply() {
+                self.data = data;
+                return Ok(());
+            }
+            // Both sides may have started a transfer with the internal clock
+            self.receive_transfers(link)?;
+            link.wait()?;
+        }
+    }
+
+    fn transferring(&self) -> bool {
+        self.control & TRANSFER_START > 0
+    }
+
+    // A transfer this side is driving
+    fn clocking(&self) -> bool {
+        self.transferring() && self.control & INTERNAL_CLOCK > 0
+    }
+
+    // Divider bit clocking the transfer
+    fn clock_bit(&self) -> u16 {
+        if self.control & FAST_CLOCK > 0 {
+            4
+        } else {
+            8
+        }
+    }
+
+    // Called after the timer advanced the divider by ticks. Returns true when the serial interrupt
+    // is requested.
+    pub fn update(&mut self, divider: u16, ticks: u8) -> bool {
+        if !self.clocking() {
+            return false;
+        }
+        let mask = 1 << self.clock_bit();
+        let mut before = divider.wrapping_sub(ticks as u16 / 4 * 4);
+        for _ in 0..ticks / 4 {
+            let after = before.wrapping_add(4);
+            if before & mask > 0 && after & mask == 0 && self.shift(true) {
+                self.with_link(|serial, link| serial.receive_reply(link));
+                return true;
+            }
+            before = after;
+        }
+        false
+    }
+
+    // Shifts one bit in, a disconnected cable reads 1. Returns true when the transfer is done.
+    fn shift(&mut self, bit: bool) -> bool {
+        self.data = (self.data << 1) | bit as u8;
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.control &= !TRANSFER_START;
+            return true;
+        }
+        false
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.data,
+            _ => self.control,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xFF01 => self.data = data,
+            _ => {
+                // Rewriting SC during a transfer doesn't restart it
+                let starting = !self.transferring() && data & TRANSFER_START > 0;
+                let was_clocking = self.clocking();
+                self.control = data;
+                if starting {
+                    self.bits = 8;
+                    if self.print {
+                        print!("{}", self.data as char);
+                        let _ = stdout().flush();
+                    }
+                }
+                // The other side gets the byte as soon as this side drives the clock, even when
+                // switching to the internal clock in the middle of a transfer
+                if self.clocking() && !was_clocking {
+                    let data = self.data;
+                    self.with_link(|_, link| link.start_transfer(data));
+                }
+            }
+        }
+    }
+}
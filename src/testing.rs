@@ -0,0 +1,40 @@
+use crate::controls::Controls;
+use crate::hardware::Cpu;
+use crate::master::Master;
+use crate::memory::Memory;
+use crate::{boot, power_on, Config};
+use std::env;
+use std::fs;
+
+// Unit test helper: a DMG without boot ROM, about to run program from 0x0100.
+// The ROM image is written to a temporary file named after the test.
+pub fn power_on_program(name: &str, program: &[u8]) -> (Cpu, Controls, Master, Memory) {
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0100 + program.len()].copy_from_slice(program);
+    let rom_path = env::temp_dir().join(format!("rust_boy_{}.gb", name));
+    fs::write(&rom_path, rom).expect("Unable to write test ROM");
+
+    let config = Config {
+        rom_path: &rom_path,
+        debug: 0,
+        full_screen: false,
+        framerate: 0.0,
+        video_sync: false,
+        camera_source: None,
+        zip_entry: None,
+        patch_path: None,
+        boot_rom_path: None,
+        model: boot::Model::Dmg,
+        break_on_illegal: false,
+        record_audio: None,
+        record_channels: false,
+        scope: false,
+        print_serial: false,
+        link_host: None,
+        link_connect: None,
+    };
+    let mut mem = Memory::new(&config).expect("Unable to load test ROM");
+    let (cpu, _, controls, master) = power_on(&config, &mut mem);
+    let _ = fs::remove_file(&rom_path);
+    (cpu, controls, master, mem)
+}
@@ -1,54 +1,182 @@
-use crate::memory::Memory;
+use serde::{Deserialize, Serialize};
 
+// Sources:
+// https://gbdev.io/pandocs/Timer_Obscure_Behaviour.html
+// https://github.com/Gekkio/mooneye-test-suite (acceptance/timer tests)
+//
+// DIV is the upper byte of a 16-bit divider counting T-cycles. TIMA increments on the falling edge
+// of one of its bits (selected by TAC) ANDed with the enable bit, so resetting DIV or changing TAC
+// can increment TIMA too.
+
+#[derive(Serialize, Deserialize)]
 pub struct Timer {
-    pub divider_ticks: u16, //update every 256
-    pub division: u16,
-    pub timer_ticks: u16, //Update every division
-    pub timer_enb: bool,
+    pub divider: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    overflow: bool,  // TIMA overflowed last cycle, it reads 0 until reloaded
+    reloading: bool, // TIMA was reloaded from TMA this cycle
 }
 
 impl Timer {
-    pub fn update(&mut self, ticks: u8, mem: &mut Memory) {
-        self.divider_ticks += ticks as u16;
-
-        //Up divider
-        if self.divider_ticks > 255 {
-            //println!("/!\\ DIVIDER OVERFLOW");
-            self.divider_ticks = 0;
-            // Doing this without going through the mem.write() function is essential, as it would reset to 0
-            mem.ram[0x7f04] = mem.ram[0x7f04].wrapping_add(1);
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: false,
+            reloading: false,
         }
+    }
 
-        //update division value
-        let bits: u8 = mem.read(0xff07) & 0b00000011;
-        match bits {
-            0b00 => self.division = 1024,
-            0b01 => self.division = 16,
-            0b10 => self.division = 64,
-            0b11 => self.division = 256,
-            _ => {}
+    // Returns true when the timer interrupt is requested
+    pub fn update(&mut self, ticks: u8) -> bool {
+        let mut interrupt = false;
+        for _ in 0..ticks / 4 {
+            interrupt |= self.cycle();
         }
+        interrupt
+    }
 
-        //update enable value
-        if (mem.read(0xff07) & 0b00000100) > 0 {
-            self.timer_enb = true;
-        } else {
-            self.timer_enb = false;
+    // One M-cycle
+    fn cycle(&mut self) -> bool {
+        self.reloading = false;
+        let interrupt = self.overflow;
+        if self.overflow {
+            // Reloading happens one cycle after the overflow
+            self.overflow = false;
+            self.tima = self.tma;
+            self.reloading = true;
         }
 
-        //update timer
-        if self.timer_enb {
-            self.timer_ticks += ticks as u16;
-            if self.timer_ticks > self.division {
-                if mem.read(0xff05) == 255 {
-                    //println!("/!\\ TIMER OVERFLOW");
-                    mem.write(0xff05, mem.read(0xff06));
-                    mem.write(0xff0f, mem.read(0xff0f) | 0b00000100);
-                } else {
-                    mem.write(0xff05, mem.read(0xff05) + 1);
+        let before = self.signal();
+        self.divider = self.divider.wrapping_add(4);
+        if before && !self.signal() {
+            self.increment();
+        }
+        interrupt
+    }
+
+    // Divider bit selected by TAC, ANDed with the enable bit
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9, // 4096 Hz
+            0b01 => 3, // 262144 Hz
+            0b10 => 5, // 65536 Hz
+            _ => 7,    // 16384 Hz
+        };
+        self.tac & 0b100 > 0 && self.divider & (1 << bit) > 0
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow = overflow;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.divider >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            _ => self.tac,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let before = self.signal();
+        match addr {
+            0xFF04 => self.divider = 0,
+            0xFF05 => {
+                // Ignored on the reload cycle, cancels the reload and interrupt before it
+                if !self.reloading {
+                    self.tima = data;
+                    self.overflow = false;
                 }
-                self.timer_ticks = 0;
             }
+            0xFF06 => {
+                // On the reload cycle, the new TMA goes straight to TIMA
+                self.tma = data;
+                if self.reloading {
+                    self.tima = data;
+                }
+            }
+            _ => self.tac = data & 0b111,
         }
+        // Writing DIV or TAC can make the signal fall
+        if before && !self.signal() {
+            self.increment();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+
+    // Enabled, TIMA increments on the falling edge of divider bit 3
+    const TAC_BIT_3: u8 = 0b101;
+
+    // Timer whose TIMA just overflowed, TMA is reloaded on the next M-cycle
+    fn overflowed_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.tac = TAC_BIT_3;
+        timer.tima = 0xFF;
+        timer.tma = 0x42;
+        timer.divider = 0x000C;
+        assert!(!timer.update(4));
+        assert_eq!(timer.read(0xFF05), 0x00);
+        timer
+    }
+
+    #[test]
+    fn div_write_increments_on_falling_edge() {
+        let mut timer = Timer::new();
+        timer.tac = TAC_BIT_3;
+        timer.divider = 0x0008;
+        timer.write(0xFF04, 0x00);
+        assert_eq!(timer.read(0xFF04), 0x00);
+        assert_eq!(timer.tima, 1);
+
+        // No edge with the selected bit already low
+        timer.write(0xFF04, 0x00);
+        assert_eq!(timer.tima, 1);
+    }
+
+    #[test]
+    fn tac_change_increments_on_falling_edge() {
+        let mut timer = Timer::new();
+        timer.tac = TAC_BIT_3;
+        timer.divider = 0x0008;
+
+        // Selecting bit 9, which is low
+        timer.write(0xFF07, 0b100);
+        assert_eq!(timer.tima, 1);
+
+        // Disabling the timer while the selected bit is high
+        timer.write(0xFF07, TAC_BIT_3);
+        timer.write(0xFF07, 0b001);
+        assert_eq!(timer.tima, 2);
+    }
+
+    #[test]
+    fn tima_write_ignored_on_reload_cycle() {
+        let mut timer = overflowed_timer();
+        assert!(timer.update(4));
+        assert_eq!(timer.tima, 0x42);
+
+        timer.write(0xFF05, 0x10);
+        assert_eq!(timer.tima, 0x42);
+    }
+
+    #[test]
+    fn tma_write_copied_on_reload_cycle() {
+        let mut timer = overflowed_timer();
+        assert!(timer.update(4));
+
+        timer.write(0xFF06, 0x99);
+        assert_eq!(timer.tma, 0x99);
+        assert_eq!(timer.tima, 0x99);
     }
 }